@@ -9,7 +9,10 @@ pub enum FlockError {
     InternalError,
 
     #[display(fmt = "Bad Request")]
-    BadRequest
+    BadRequest,
+
+    #[display(fmt = "Unauthorized")]
+    Unauthorized
 }
 
 impl From<reqwest::Error> for FlockError {
@@ -40,6 +43,34 @@ impl From<serde_json::Error> for FlockError {
     }
 }
 
+impl From<rmp_serde::encode::Error> for FlockError {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        println!("MessagePack Error: {:?}", error);
+        FlockError::InternalError
+    }
+}
+
+impl From<rmp_serde::decode::Error> for FlockError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        println!("MessagePack Error: {:?}", error);
+        FlockError::BadRequest
+    }
+}
+
+impl From<serde_cbor::Error> for FlockError {
+    fn from(error: serde_cbor::Error) -> Self {
+        println!("CBOR Error: {:?}", error);
+        FlockError::BadRequest
+    }
+}
+
+impl From<actix_web_actors::ws::HandshakeError> for FlockError {
+    fn from(error: actix_web_actors::ws::HandshakeError) -> Self {
+        println!("Handshake Error: {:?}", error);
+        FlockError::BadRequest
+    }
+}
+
 impl From<actix_web::Error> for FlockError {
     fn from(error: actix_web::Error) -> Self {
         println!("Actix Web: {:?}", error);
@@ -47,6 +78,7 @@ impl From<actix_web::Error> for FlockError {
     }    
 }
 
+#[cfg(feature = "watchtower")]
 impl From<watchtower_client::Error> for FlockError {
     fn from(error: watchtower_client::Error) -> Self {
         println!("WatchtowerClient Error: {:?}", error);
@@ -54,6 +86,13 @@ impl From<watchtower_client::Error> for FlockError {
     }
 }
 
+impl From<trust_dns_resolver::error::ResolveError> for FlockError {
+    fn from(error: trust_dns_resolver::error::ResolveError) -> Self {
+        println!("DNS Error: {:?}", error);
+        FlockError::InternalError
+    }
+}
+
 impl From<std::io::Error> for FlockError {
     fn from(error: std::io::Error) -> Self {
         println!("StdIoError: {:?}", error);
@@ -61,6 +100,20 @@ impl From<std::io::Error> for FlockError {
     }
 }
 
+impl From<redis_async::error::Error> for FlockError {
+    fn from(error: redis_async::error::Error) -> Self {
+        println!("Redis Error: {:?}", error);
+        FlockError::InternalError
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for FlockError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        println!("Token Error: {:?}", error);
+        FlockError::Unauthorized
+    }
+}
+
 impl error::ResponseError for FlockError {
     fn error_response(&self) -> HttpResponse {
         HttpResponseBuilder::new(self.status_code())
@@ -71,7 +124,8 @@ impl error::ResponseError for FlockError {
     fn status_code(&self) -> StatusCode {
         match *self {
             FlockError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            FlockError::BadRequest => StatusCode::BAD_REQUEST
+            FlockError::BadRequest => StatusCode::BAD_REQUEST,
+            FlockError::Unauthorized => StatusCode::UNAUTHORIZED
         }
     }
 }
@@ -9,7 +9,7 @@ mod resources;
 
 use crate::{
     types::AppState,
-    resources::Dispatcher,
+    resources::{Coordinator, Dispatchers, Fanout, RedisListener, Upstream, discovery},
     utils::env
 };
 
@@ -17,10 +17,35 @@ use crate::{
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "flight=info,actix_web=info,actix_redis=info,watchtower_client=info");
     env_logger::init();
+    telemetry::init("flight");
 
-    let dispatcher = Dispatcher::new().start();
+    if env::get_token_config().secret.is_none() {
+        log::warn!("FLIGHT_TOKEN_SECRET is not set, WebSocket connections will not be authenticated");
+    }
+
+    // events would silently never arrive without the listener, so flight refuses to start without it
+    let listener = if env::get_delivery_mode() == env::DeliveryMode::Redis {
+        match RedisListener::connect().await {
+            Ok(listener) => {
+                log::info!("Listening for events on Redis");
+                Some(listener)
+            }
+            Err(error) => {
+                log::error!("Failed to connect to Redis: {}", error);
+                return Err(std::io::Error::other(error.to_string()));
+            }
+        }
+    } else {
+        None
+    };
+
+    let fanout = Fanout::new();
+    let upstream = Upstream::new(discovery::from_env());
+    let coordinator = Coordinator::new(fanout.clone(), upstream.clone(), listener).start();
+    let dispatchers = Dispatchers::start(env::get_dispatcher_shards(), fanout.clone(), coordinator, upstream);
     let app_state = AppState {
-        dispatcher,
+        dispatchers,
+        fanout
     };
 
     let instance_info = env::get_instance_info();
@@ -31,10 +56,16 @@ async fn main() -> std::io::Result<()> {
         .service(
             web::scope("/api/v1")
             .configure(routes::api::v1::event::config)
+            .configure(routes::api::v1::stream::config)
+            .configure(routes::api::v1::presence::config)
         )
         .service(web::resource("/ws").route(web::get().to(routes::ws::index)))
+        .service(web::resource("/metrics").route(web::get().to(routes::metrics::index)))
     )
     .bind(format!("0.0.0.0:{}", instance_info.port))?
     .run()
-    .await
+    .await?;
+
+    telemetry::shutdown();
+    Ok(())
 }
@@ -0,0 +1,244 @@
+use actix::{
+    Actor, AsyncContext, Context, Handler, Message, WrapFuture, ActorFuture,
+    prelude::ResponseActFuture,
+};
+use serde_json::to_string;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration
+};
+use tokio::sync::Mutex;
+use log::warn;
+
+use crate::{
+    resources::{Fanout, RedisListener, Upstream},
+    utils::env,
+    types::Result
+};
+
+/// Duties of the instance as a whole, which the dispatcher shards leave to the coordinator.
+pub enum CoordinatorMessage {
+    /// Subscribes this instance to a topic a socket subscribed to, and listens on its Redis channel, unless it already is.
+    Subscribe(String),
+    /// Unsubscribes this instance from a topic once none of its sockets is subscribed to it anymore.
+    Unsubscribe(String),
+    Reset,
+    Heartbeat
+}
+
+impl Message for CoordinatorMessage {
+    type Result = Result<bool>;
+}
+
+pub struct Coordinator {
+    upstream: Upstream,
+    fanout: Fanout,
+    listener: Option<RedisListener>,
+    auth: env::AuthInfo,
+    // whether this instance is subscribed to each topic, locked while subscribing to or unsubscribing from it
+    // so that the changes to a topic reach traffic-control in the order they were made
+    topics: HashMap<String, Arc<Mutex<bool>>>
+}
+
+impl Coordinator {
+    pub fn new(fanout: Fanout, upstream: Upstream, listener: Option<RedisListener>) -> Coordinator {
+        Coordinator {
+            upstream,
+            fanout,
+            listener,
+            auth: env::get_auth_info(),
+            topics: HashMap::new()
+        }
+    }
+
+    fn topic(&mut self, topic: &str) -> Arc<Mutex<bool>> {
+        self.topics.entry(topic.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(false)))
+            .clone()
+    }
+
+    // forgets the topics this instance is not subscribed to once nothing waits on them anymore
+    fn release(&mut self, topic: Option<&str>) {
+        let idle = |state: &Arc<Mutex<bool>>| Arc::strong_count(state) == 1 && state.try_lock().is_ok_and(|subscribed| !*subscribed);
+        match topic {
+            Some(topic) => {
+                if self.topics.get(topic).is_some_and(idle) {
+                    self.topics.remove(topic);
+                }
+            }
+            None => self.topics.retain(|_, state| !idle(state))
+        }
+    }
+
+    async fn subscribe(topic: String, upstream: Upstream, auth: env::AuthInfo) -> Result<()> {
+        let instance_info = &env::get_instance_info();
+        let body = to_string(instance_info)?;
+        let (topic, auth, body) = (&topic, &auth, &body);
+        upstream.call("subscribe", true, |http_client, base_url| async move {
+            http_client
+                .put(&format!("http://{}/api/v1/subscription/{}", base_url, topic))
+                .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
+                .basic_auth(&auth.username, Some(&auth.password))
+                .body(body.to_string()).header("content-type", "application/json")
+                .send().await?
+                .error_for_status()?;
+            Ok(())
+        }).await
+    }
+
+    async fn unsubscribe(topic: String, upstream: Upstream, auth: env::AuthInfo) -> Result<()> {
+        let instance_info = &env::get_instance_info();
+        let body = to_string(instance_info)?;
+        let (topic, auth, body) = (&topic, &auth, &body);
+        upstream.call("unsubscribe", true, |http_client, base_url| async move {
+            http_client
+                .delete(&format!("http://{}/api/v1/subscription/{}", base_url, topic))
+                .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
+                .basic_auth(&auth.username, Some(&auth.password))
+                .body(body.to_string()).header("content-type", "application/json")
+                .send().await?
+                .error_for_status()?;
+            Ok(())
+        }).await
+    }
+
+    /// Renews the lease of this instance, returning false when traffic-control no longer knew about it.
+    async fn heartbeat(upstream: Upstream, auth: env::AuthInfo) -> Result<bool> {
+        let instance_info = &env::get_instance_info();
+        let auth = &auth;
+        // any traffic-control instance can renew the lease, which lives in Redis
+        let status = upstream.call("heartbeat", true, |http_client, base_url| async move {
+            let res = http_client
+                .put(&format!("http://{}/api/v1/heartbeat", base_url))
+                .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
+                .basic_auth(&auth.username, Some(&auth.password))
+                .send().await?
+                .error_for_status()?;
+            Ok(res.status())
+        }).await?;
+        Ok(status != reqwest::StatusCode::CREATED)
+    }
+
+    async fn reset(upstream: Upstream, auth: env::AuthInfo) -> Result<()> {
+        let instance_info = &env::get_instance_info();
+        let auth = &auth;
+        upstream.call("reset", true, |http_client, base_url| async move {
+            http_client
+                .delete(&format!("http://{}/api/v1/subscription", base_url))
+                .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
+                .basic_auth(&auth.username, Some(&auth.password))
+                .header("content-type", "application/json")
+                .send().await?
+                .error_for_status()?;
+            Ok(())
+        }).await
+    }
+}
+
+impl Actor for Coordinator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.notify(CoordinatorMessage::Reset);
+        ctx.run_interval(Duration::from_secs(env::get_heartbeat_interval()), |_, ctx| {
+            ctx.notify(CoordinatorMessage::Heartbeat);
+        });
+    }
+}
+
+impl Handler<CoordinatorMessage> for Coordinator {
+    type Result = ResponseActFuture<Self, Result<bool>>;
+
+    fn handle(&mut self, msg: CoordinatorMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        match msg {
+            CoordinatorMessage::Subscribe(topic) => {
+                let state = self.topic(&topic);
+                let listener = self.listener.clone();
+                let fanout = self.fanout.clone();
+                let upstream = self.upstream.clone();
+                let auth = self.auth.clone();
+                let name = topic.to_string();
+                Box::pin(async move {
+                    let mut subscribed = state.lock().await;
+                    if !*subscribed {
+                        Self::subscribe(topic.to_string(), upstream.clone(), auth.clone()).await?;
+                        if let Some(listener) = listener {
+                            if let Err(error) = listener.listen(&topic, fanout).await {
+                                if let Err(error) = Self::unsubscribe(topic.to_string(), upstream, auth).await {
+                                    warn!("Failed to unsubscribe from topic {}: {}", topic, error);
+                                }
+                                return Err(error);
+                            }
+                        }
+                        *subscribed = true;
+                    }
+                    Ok(true)
+                }.into_actor(self).map(move |result, act, _| {
+                    act.release(Some(&name));
+                    result
+                }))
+            }
+            CoordinatorMessage::Unsubscribe(topic) => {
+                let state = self.topic(&topic);
+                let listener = self.listener.clone();
+                let fanout = self.fanout.clone();
+                let upstream = self.upstream.clone();
+                let auth = self.auth.clone();
+                let name = topic.to_string();
+                Box::pin(async move {
+                    let mut subscribed = state.lock().await;
+                    // a socket may have subscribed to the topic again in the meantime
+                    if *subscribed && !fanout.has_subscribers(&topic) {
+                        if let Some(listener) = listener {
+                            listener.unlisten(&topic);
+                        }
+                        *subscribed = false;
+                        Self::unsubscribe(topic, upstream, auth).await?;
+                    }
+                    Ok(true)
+                }.into_actor(self).map(move |result, act, _| {
+                    act.release(Some(&name));
+                    result
+                }))
+            }
+            CoordinatorMessage::Reset => {
+                let upstream = self.upstream.clone();
+                let auth = self.auth.clone();
+                Box::pin(async {
+                    Self::reset(upstream, auth).await?;
+                    Ok(true)
+                }.into_actor(self))
+            }
+            CoordinatorMessage::Heartbeat => {
+                let topics: Vec<(String, Arc<Mutex<bool>>)> = self.topics.iter()
+                    .map(|(topic, state)| (topic.to_string(), state.clone()))
+                    .collect();
+                let upstream = self.upstream.clone();
+                let auth = self.auth.clone();
+                Box::pin(async move {
+                    // instances started since are picked up along the way
+                    if let Err(error) = upstream.discover().await {
+                        warn!("Failed to discover traffic-control instances: {}", error);
+                    }
+                    if !Self::heartbeat(upstream.clone(), auth.clone()).await? {
+                        // the subscriptions of this instance were purged, e.g. after missing heartbeats
+                        if !topics.is_empty() {
+                            warn!("Lease was lost, subscribing to {} topics again", topics.len());
+                        }
+                        for (topic, state) in topics {
+                            let subscribed = state.lock().await;
+                            if *subscribed {
+                                Self::subscribe(topic, upstream.clone(), auth.clone()).await?;
+                            }
+                        }
+                    }
+                    Ok(true)
+                }.into_actor(self).map(|result, act, _| {
+                    act.release(None);
+                    result
+                }))
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use redis_async::{client::{paired_connect, PairedConnection}, resp_array};
+use std::{net::ToSocketAddrs, sync::Arc};
+use tokio::sync::Mutex;
+use trust_dns_resolver::TokioAsyncResolver;
+#[cfg(feature = "watchtower")]
+use watchtower_client::WatchtowerClient;
+
+use crate::{
+    utils::{env::{self, DiscoveryMode}, time},
+    types::{Result, Error}
+};
+
+#[cfg(feature = "watchtower")]
+const TRAFFIC_CONTROL_SERVICE_ID: &str = "traffic-control";
+// lookups sent to watchtower at once, repeated until they find no new instance
+#[cfg(feature = "watchtower")]
+const WATCHTOWER_LOOKUPS: usize = 8;
+// sorted set of the traffic-control instances registered in Redis, scored by the expiry of their registration
+const REDIS_INSTANCES_KEY: &str = "services:traffic-control";
+
+/// Finds the traffic-control instances, as `host:port`.
+pub trait Discovery: Send + Sync {
+    fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>>;
+}
+
+/// Asks watchtower, which returns one of the registered instances at a time, as many times as it takes
+/// to stop finding new ones.
+#[cfg(feature = "watchtower")]
+pub struct WatchtowerDiscovery {
+    client: WatchtowerClient
+}
+
+#[cfg(feature = "watchtower")]
+impl Discovery for WatchtowerDiscovery {
+    fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>> {
+        async move {
+            let mut instances: Vec<String> = Vec::new();
+            loop {
+                let lookups = (0..WATCHTOWER_LOOKUPS).map(|_| self.client.get_service_url(TRAFFIC_CONTROL_SERVICE_ID));
+                let mut found = false;
+                let mut failure = None;
+                for result in futures_util::future::join_all(lookups).await {
+                    match result {
+                        Ok(instance) => if !instances.contains(&instance) {
+                            instances.push(instance);
+                            found = true;
+                        },
+                        Err(error) => failure = Some(error)
+                    }
+                }
+                if !found {
+                    // the instances found despite failed lookups are still worth returning
+                    return match failure {
+                        Some(error) if instances.is_empty() => Err(error.into()),
+                        _ => Ok(instances)
+                    };
+                }
+            }
+        }.boxed_local()
+    }
+}
+
+/// Instances listed in `TRAFFIC_CONTROL_URLS`.
+pub struct StaticDiscovery {
+    urls: Vec<String>
+}
+
+impl Discovery for StaticDiscovery {
+    fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>> {
+        let urls = self.urls.clone();
+        async move {
+            Ok(urls)
+        }.boxed_local()
+    }
+}
+
+/// Looks up the SRV records of a name, falling back to its A and AAAA records on a fixed port.
+pub struct DnsDiscovery {
+    name: String,
+    port: u16,
+    // created on first use and kept for every lookup after it
+    resolver: Mutex<Option<TokioAsyncResolver>>
+}
+
+impl DnsDiscovery {
+    async fn resolver(&self) -> Result<TokioAsyncResolver> {
+        let mut resolver = self.resolver.lock().await;
+        if let Some(resolver) = resolver.as_ref() {
+            return Ok(resolver.clone());
+        }
+        let created = TokioAsyncResolver::tokio_from_system_conf().await?;
+        *resolver = Some(created.clone());
+        Ok(created)
+    }
+}
+
+impl Discovery for DnsDiscovery {
+    fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>> {
+        async move {
+            let resolver = self.resolver().await?;
+            if let Ok(records) = resolver.srv_lookup(self.name.as_str()).await {
+                let instances: Vec<String> = records.iter()
+                    .map(|srv| format!("{}:{}", srv.target().to_utf8().trim_end_matches('.'), srv.port()))
+                    .collect();
+                if !instances.is_empty() {
+                    return Ok(instances);
+                }
+            }
+
+            let ips = resolver.lookup_ip(self.name.as_str()).await?;
+            Ok(ips.iter().map(|ip| std::net::SocketAddr::new(ip, self.port).to_string()).collect())
+        }.boxed_local()
+    }
+}
+
+/// Reads the instances that registered themselves in Redis and whose registration has not expired.
+pub struct RedisDiscovery {
+    host: String,
+    port: u16,
+    // connected on first use and kept until a command fails on it
+    connection: Mutex<Option<PairedConnection>>
+}
+
+impl RedisDiscovery {
+    async fn connection(&self) -> Result<PairedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+        let addr = format!("{}:{}", self.host, self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::InternalError)?;
+        let connected = paired_connect(&addr).await?;
+        *connection = Some(connected.clone());
+        Ok(connected)
+    }
+}
+
+impl Discovery for RedisDiscovery {
+    fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>> {
+        async move {
+            let connection = self.connection().await?;
+            let now = time::get_time_since_epoch()?;
+            match connection.send(resp_array!["ZRANGEBYSCORE", REDIS_INSTANCES_KEY, now.to_string(), "+inf"]).await {
+                Ok(instances) => Ok(instances),
+                Err(error) => {
+                    // connects again on the next lookup
+                    self.connection.lock().await.take();
+                    Err(error.into())
+                }
+            }
+        }.boxed_local()
+    }
+}
+
+/// Builds the discovery selected by `DISCOVERY`.
+pub fn from_env() -> Arc<dyn Discovery> {
+    let config = env::get_discovery_config();
+    log::info!("Discovering traffic-control with {:?}", config.mode);
+    match config.mode {
+        #[cfg(feature = "watchtower")]
+        DiscoveryMode::Watchtower => {
+            let watchtower_config = env::get_watchtower_config();
+            Arc::new(WatchtowerDiscovery {
+                client: WatchtowerClient::new(watchtower_config.urls, &watchtower_config.username, &watchtower_config.password)
+            })
+        }
+        DiscoveryMode::Static => Arc::new(StaticDiscovery { urls: config.urls }),
+        DiscoveryMode::Dns => Arc::new(DnsDiscovery {
+            name: config.dns_name,
+            port: config.dns_port,
+            resolver: Mutex::new(None)
+        }),
+        DiscoveryMode::Redis => {
+            let redis_info = env::get_redis_info();
+            Arc::new(RedisDiscovery {
+                host: redis_info.host,
+                port: redis_info.port,
+                connection: Mutex::new(None)
+            })
+        }
+    }
+}
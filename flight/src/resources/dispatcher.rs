@@ -1,156 +1,177 @@
 use actix::{
-    Actor, AsyncContext, Context, Handler, Message, Addr,
+    Actor, Arbiter, AsyncContext, Context, Handler, Message, Addr,
     prelude::ResponseFuture,
 };
 use std::{
-    collections::HashMap,
-    sync::Arc
+    collections::{HashMap, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant}
 };
+use actix_web::web::Bytes;
 use log::warn;
-use serde_json::{json, Value, to_string};
-use watchtower_client::WatchtowerClient;
+use opentelemetry::{KeyValue, trace::SpanKind};
+use serde_json::json;
 
 use crate::{
-    resources::{SubscriptionTable, MyWs, ws::WsMessage},
-    utils::env,
-    types::{Event, Result}
+    resources::{Fanout, MyWs, Upstream, TopicAuthorizer, ClaimsAuthorizer, Coordinator, CoordinatorMessage, ws::{WsMessage, ErrorCode}},
+    utils::{env, topic, encoding::Encoding, metrics::metrics},
+    types::{Claims, HistoryEntry, Result, JSON_CONTENT_TYPE, BINARY_CONTENT_TYPE}
 };
 
 pub enum DispatcherMessage {
-    Event(Event),
     RegisterWS {
         addr: Addr<MyWs>,
-        socket_id: String
+        socket_id: String,
+        claims: Option<Claims>,
+        encoding: Encoding
     },
     Subscribe {
         socket_id: String,
         topic: String,
-        request_id: String 
+        request_id: String,
+        since_seq: Option<u64>
     },
     Unsubscribe {
         socket_id: String,
         topic: String,
         request_id: String 
     },
+    Publish {
+        socket_id: String,
+        topic: String,
+        payload: Bytes,
+        binary: bool,
+        request_id: String
+    },
     Close(String),
-    Reset
+    // sent by a shard to itself to measure how long messages wait in its mailbox
+    Probe(Instant)
 }
 
 impl Message for DispatcherMessage {
     type Result = Result<bool>;
 }
 
+struct Socket {
+    addr: Arc<Addr<MyWs>>,
+    claims: Option<Claims>
+}
+
+/// A shard handling the commands of the sockets assigned to it. Shards share the subscriptions
+/// through the fanout and leave subscribing to and unsubscribing from traffic-control to the coordinator.
 pub struct Dispatcher {
-    http_client: Arc<reqwest::Client>,
-    watchtower_client: Arc<WatchtowerClient>,
-    subscription_table: SubscriptionTable,
-    ws_table: HashMap<String, Arc<Addr<MyWs>>>,
+    upstream: Upstream,
+    fanout: Fanout,
+    coordinator: Addr<Coordinator>,
+    ws_table: HashMap<String, Socket>,
+    authorizer: Arc<dyn TopicAuthorizer>,
     auth: env::AuthInfo
 }
 
-const TRAFFIC_CONTROL_SERVICE_ID: &str = "traffic-control";
+const PROBE_INTERVAL_SEC: u64 = 5;
+
+/// Dispatcher shards, each running on an arbiter of its own, with sockets assigned to them by id.
+#[derive(Clone)]
+pub struct Dispatchers {
+    shards: Arc<Vec<Addr<Dispatcher>>>
+}
+
+impl Dispatchers {
+    pub fn start(count: usize, fanout: Fanout, coordinator: Addr<Coordinator>, upstream: Upstream) -> Dispatchers {
+        let shards = (0..count.max(1)).map(|_| {
+            let fanout = fanout.clone();
+            let coordinator = coordinator.clone();
+            let upstream = upstream.clone();
+            Dispatcher::start_in_arbiter(&Arbiter::new(), move |_| Dispatcher::new(fanout, coordinator, upstream))
+        }).collect();
+        Dispatchers {
+            shards: Arc::new(shards)
+        }
+    }
+
+    /// Returns the shard handling the commands of a socket.
+    pub fn get(&self, socket_id: &str) -> &Addr<Dispatcher> {
+        let mut hasher = DefaultHasher::new();
+        socket_id.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
 
 impl Dispatcher {
-    pub fn new() -> Dispatcher {
-        let watchtower_config = env::get_watchtower_config();
+    pub fn new(fanout: Fanout, coordinator: Addr<Coordinator>, upstream: Upstream) -> Dispatcher {
+        Self::with_authorizer(fanout, coordinator, upstream, Arc::new(ClaimsAuthorizer))
+    }
+
+    pub fn with_authorizer(fanout: Fanout, coordinator: Addr<Coordinator>, upstream: Upstream, authorizer: Arc<dyn TopicAuthorizer>) -> Dispatcher {
         Dispatcher {
-            http_client: Arc::new(reqwest::Client::new()),
-            watchtower_client: Arc::new(WatchtowerClient::new(watchtower_config.urls, &watchtower_config.username, &watchtower_config.password)),
-            subscription_table: SubscriptionTable::new(),
+            upstream,
+            fanout,
+            coordinator,
             ws_table: HashMap::new(),
+            authorizer,
             auth: env::get_auth_info()
         }
     }
 
-    fn broadcast_event(&self, topic: &str, message: &str) -> ResponseFuture<Result<bool>> {
-        match serde_json::from_str(message) {
-            Ok(payload) => {
-                let payload: Value = payload;
-                let value = json!({
-                    "topic": topic,
-                    "payload": payload,
-                    "type": "event"
-                });
-        
-                let socket_ids = self.subscription_table.get(topic);
-        
-                let mut sockets = Vec::new();
-                for socket_id in socket_ids {
-                    if let Some(socket) = self.ws_table.get(socket_id) {
-                        sockets.push(socket.clone());
-                    }
-                }
-                
-                let topic = topic.to_string();
-                Box::pin(async move {
-                    for socket in sockets {
-                        match socket.send(WsMessage::Text(value.to_string())).await {
-                            Ok(Ok(_)) => (),
-                            _ => {
-                                warn!("Failed to send an event for topic {}", topic);
-                            }
-                        };
-                    }
-                    Ok(true)
-                })
-            },
-            Err(error) => {
-                warn!("Failed to parse message: {}", error); 
-                Box::pin(async move {
-                    Ok(true)
-                })
-            }
-        }
+    // joins and leaves are only tracked for concrete topics that are not presence topics themselves
+    fn has_presence(topic: &str) -> bool {
+        !topic::is_pattern(topic) && !topic::is_presence(topic)
     }
 
-    async fn subscribe(topic: String, watchtower_client: Arc<WatchtowerClient>, http_client: Arc<reqwest::Client>, auth: env::AuthInfo) -> Result<()> {
-        let base_url = watchtower_client.get_service_url(TRAFFIC_CONTROL_SERVICE_ID).await?;
-        let url = format!("http://{}/api/v1/subscription/{}", base_url, topic);
-
-        let instance_info = &env::get_instance_info();
-        http_client
-            .put(&url)
-            .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
-            .basic_auth(auth.username, Some(auth.password))
-            .body(to_string(&env::get_instance_info())?).header("content-type", "application/json")
-            .send().await?;
-        Ok(())
+    async fn publish(topic: String, payload: Bytes, binary: bool, traceparent: Option<String>, upstream: Upstream, auth: env::AuthInfo) -> Result<()> {
+        let content_type = if binary { BINARY_CONTENT_TYPE } else { JSON_CONTENT_TYPE };
+        let (topic, payload, traceparent, auth) = (&topic, &payload, &traceparent, &auth);
+        // a publish is only retried when it could not reach traffic-control, so that it is never delivered twice
+        upstream.call("publish", false, |http_client, base_url| async move {
+            let mut req = http_client
+                .post(&format!("http://{}/api/v1/event/{}", base_url, topic))
+                .basic_auth(&auth.username, Some(&auth.password));
+            if let Some(traceparent) = traceparent {
+                req = req.header(telemetry::TRACEPARENT_HEADER, traceparent.to_string());
+            }
+            req
+                .body(payload.clone()).header("content-type", content_type)
+                .send().await?
+                .error_for_status()?;
+            Ok(())
+        }).await
     }
 
-    async fn unsubscribe(topic: String, watchtower_client: Arc<WatchtowerClient>, http_client: Arc<reqwest::Client>, auth: env::AuthInfo) -> Result<()> {
-        let base_url = watchtower_client.get_service_url(TRAFFIC_CONTROL_SERVICE_ID).await?;
-        let url = format!("http://{}/api/v1/subscription/{}", base_url, topic);
-
-        let instance_info = &env::get_instance_info();
-        http_client
-            .delete(&url)
-            .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
-            .basic_auth(auth.username, Some(auth.password))
-            .body(to_string(&env::get_instance_info())?).header("content-type", "application/json")
-            .send().await?;
-        Ok(()) 
+    /// Publishes a join or leave event of a socket on the presence topic of `topic`.
+    async fn announce(topic: String, action: &str, user: Option<String>, upstream: Upstream, auth: env::AuthInfo) {
+        let payload = json!({
+            "action": action,
+            "topic": topic,
+            "user": user
+        }).to_string();
+        if let Err(error) = Self::publish(topic::to_presence(&topic), Bytes::from(payload), false, None, upstream, auth).await {
+            warn!("Failed to publish presence of topic {}: {}", topic, error);
+        }
     }
-    
-    async fn reset(watchtower_client: Arc<WatchtowerClient>, http_client: Arc<reqwest::Client>, auth: env::AuthInfo) -> Result<()> {
-        let base_url = watchtower_client.get_service_url(TRAFFIC_CONTROL_SERVICE_ID).await?;
-        let url = format!("http://{}/api/v1/subscription", base_url);
-
-        let instance_info = &env::get_instance_info();
-        http_client
-            .delete(&url)
-            .query(&[("host", &instance_info.host), ("port", &instance_info.port.to_string())])
-            .basic_auth(auth.username, Some(auth.password))
-            .header("content-type", "application/json")
-            .send().await?;
-        Ok(()) 
+
+    async fn history(topic: String, since_seq: u64, upstream: Upstream, auth: env::AuthInfo) -> Result<Vec<HistoryEntry>> {
+        let (topic, auth) = (&topic, &auth);
+        let body = upstream.call("history", true, |http_client, base_url| async move {
+            http_client
+                .get(&format!("http://{}/api/v1/history/{}", base_url, topic))
+                .query(&[("since_seq", since_seq)])
+                .basic_auth(&auth.username, Some(&auth.password))
+                .send().await?
+                .error_for_status()?
+                .text().await
+        }).await?;
+        Ok(serde_json::from_str(&body)?)
     }
 }
 
 impl Actor for Dispatcher {
     type Context = Context<Self>;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.notify(DispatcherMessage::Reset);
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(PROBE_INTERVAL_SEC), |_, ctx| {
+            ctx.address().do_send(DispatcherMessage::Probe(Instant::now()));
+        });
     }
 }
 
@@ -159,62 +180,193 @@ impl Handler<DispatcherMessage> for Dispatcher {
 
     fn handle(&mut self, event: DispatcherMessage, _ctx: &mut Context<Self>) -> Self::Result {
         match event {
-            DispatcherMessage::Event(event) => {
-                self.broadcast_event(&event.topic, &event.message)
-            }
             DispatcherMessage::RegisterWS {
                 socket_id,
-                addr
+                addr,
+                claims,
+                encoding
             } => {
-                self.ws_table.insert(socket_id, Arc::new(addr));
+                let user = claims.as_ref().map(|claims| claims.sub.to_string());
+                self.fanout.register(&socket_id, addr.clone(), encoding, user);
+                self.ws_table.insert(socket_id, Socket {
+                    addr: Arc::new(addr),
+                    claims
+                });
                 Box::pin(async {
                     Ok(true)
                 })
             }
-            DispatcherMessage::Subscribe { socket_id, topic, request_id } => {
+            DispatcherMessage::Subscribe { socket_id, topic, request_id, since_seq } => {
                 if let Some(socket) = self.ws_table.get(&socket_id) {
-                    let row_change = self.subscription_table.insert(&socket_id, &topic);
-                    let watchtower_client = self.watchtower_client.clone();
-                    let http_client = self.http_client.clone();
+                    // the presence of a topic is visible to whoever may subscribe to the topic itself
+                    let base_topic = topic::strip_presence(&topic);
+                    let rejection = if !topic::is_valid(&topic) {
+                        Some((ErrorCode::InvalidTopic, format!("{} is not a valid topic", topic)))
+                    } else if topic::is_presence(&topic) && topic::is_pattern(base_topic) {
+                        Some((ErrorCode::InvalidTopic, format!("presence is not available for pattern {}", base_topic)))
+                    } else {
+                        self.authorizer.authorize_subscribe(socket.claims.as_ref(), base_topic).err()
+                            .map(|reason| (ErrorCode::Forbidden, reason))
+                    };
+
+                    if let Some((code, message)) = rejection {
+                        let socket = socket.addr.clone();
+                        return Box::pin(async move {
+                            socket.send(WsMessage::Error {
+                                topic: Some(topic),
+                                request_id: Some(request_id),
+                                code,
+                                message
+                            }).await??;
+                            Ok(true)
+                        });
+                    }
+
+                    let joined = Self::has_presence(&topic) && !self.fanout.is_subscribed(&socket_id, &topic);
+                    let user = socket.claims.as_ref().map(|claims| claims.sub.to_string());
+                    self.fanout.subscribe(&socket_id, &topic);
+                    metrics().subscribes.inc();
+                    let upstream = self.upstream.clone();
                     let auth = self.auth.clone();
+                    let coordinator = self.coordinator.clone();
+                    let fanout = self.fanout.clone();
 
-                    let socket = socket.clone();
+                    let socket = socket.addr.clone();
                     Box::pin(async move {
-                        if row_change {
-                            Self::subscribe(topic.to_string(), watchtower_client, http_client, auth).await?;
+                        // the coordinator subscribes this instance to the topic unless it already is, so the socket
+                        // is only confirmed once traffic-control knows that this instance is interested in the topic
+                        let result: Result<bool> = async {
+                            coordinator.send(CoordinatorMessage::Subscribe(topic.to_string())).await?
+                        }.await;
+                        if let Err(error) = result {
+                            // the socket is not subscribed, so it must not receive the events of the topic either
+                            if fanout.unsubscribe(&socket_id, &topic) {
+                                coordinator.do_send(CoordinatorMessage::Unsubscribe(topic.to_string()));
+                            }
+                            // release the live events the socket holds back for the replay
+                            if since_seq.is_some() {
+                                socket.do_send(WsMessage::Replay { topic: topic.to_string(), events: Vec::new() });
+                            }
+                            socket.do_send(WsMessage::Error {
+                                topic: Some(topic.to_string()),
+                                request_id: Some(request_id),
+                                code: ErrorCode::UpstreamError,
+                                message: format!("unable to subscribe to {}", topic)
+                            });
+                            return Err(error);
                         }
                         
                         socket.send(WsMessage::Subscription {
-                            topic, 
+                            topic: topic.to_string(), 
                             request_id,
                             subscribed: true
                         }).await??;
+
+                        if let Some(since_seq) = since_seq {
+                            let events = match Self::history(topic.to_string(), since_seq, upstream.clone(), auth.clone()).await {
+                                Ok(events) => events,
+                                Err(error) => {
+                                    warn!("Failed to fetch history for topic {}: {}", topic, error);
+                                    Vec::new()
+                                }
+                            };
+                            socket.send(WsMessage::Replay { topic: topic.to_string(), events }).await??;
+                        }
+
+                        if joined {
+                            Self::announce(topic, "join", user, upstream, auth).await;
+                        }
                         Ok(true)
                     })
                 } else {
                     Box::pin(async move {
-                        Ok(true)
+                        Ok(false)
                     })
                 }
             }
             DispatcherMessage::Unsubscribe { socket_id, topic, request_id } => {
                 if let Some(socket) = self.ws_table.get(&socket_id) {
-                    let row_change = self.subscription_table.remove(&socket_id, &topic);
-                    let watchtower_client = self.watchtower_client.clone();
-                    let http_client = self.http_client.clone();
+                    let left = Self::has_presence(&topic) && self.fanout.is_subscribed(&socket_id, &topic);
+                    let user = socket.claims.as_ref().map(|claims| claims.sub.to_string());
+                    let row_change = self.fanout.unsubscribe(&socket_id, &topic);
+                    metrics().unsubscribes.inc();
+                    let upstream = self.upstream.clone();
                     let auth = self.auth.clone();
+                    let coordinator = self.coordinator.clone();
 
-                    let socket = socket.clone();
+                    let socket = socket.addr.clone();
                     Box::pin(async move {
                         if row_change {
-                            Self::unsubscribe(topic.to_string(), watchtower_client, http_client, auth).await?;
+                            let result: Result<bool> = async {
+                                coordinator.send(CoordinatorMessage::Unsubscribe(topic.to_string())).await?
+                            }.await;
+                            if let Err(error) = result {
+                                socket.do_send(WsMessage::Error {
+                                    topic: None,
+                                    request_id: Some(request_id),
+                                    code: ErrorCode::UpstreamError,
+                                    message: format!("unable to unsubscribe from {}", topic)
+                                });
+                                return Err(error);
+                            }
                         }
                         
                         socket.send(WsMessage::Subscription {
-                            topic, 
+                            topic: topic.to_string(), 
                             request_id,
                             subscribed: false
                         }).await??;
+
+                        if left {
+                            Self::announce(topic, "leave", user, upstream, auth).await;
+                        }
+                        Ok(true)
+                    })
+                } else {
+                    Box::pin(async move {
+                        Ok(true)
+                    })
+                }
+            }
+            DispatcherMessage::Publish { socket_id, topic, payload, binary, request_id } => {
+                if let Some(socket) = self.ws_table.get(&socket_id) {
+                    // presence topics are only written by flight itself
+                    let rejection = if !topic::is_valid(&topic) || topic::is_pattern(&topic) || topic::is_presence(&topic) {
+                        Some((ErrorCode::InvalidTopic, format!("cannot publish to {}", topic)))
+                    } else {
+                        self.authorizer.authorize_publish(socket.claims.as_ref(), &topic).err()
+                            .map(|reason| (ErrorCode::Forbidden, reason))
+                    };
+                    let upstream = self.upstream.clone();
+                    let auth = self.auth.clone();
+                    // publishes of sockets start their traces here
+                    let cx = telemetry::span("publish", SpanKind::Producer, &opentelemetry::Context::new(), vec![
+                        KeyValue::new("socket_id", socket_id.to_string()),
+                        KeyValue::new("topic", topic.to_string())
+                    ]);
+
+                    let socket = socket.addr.clone();
+                    Box::pin(async move {
+                        let error = match rejection {
+                            Some(rejection) => Some(rejection),
+                            None => match Self::publish(topic.to_string(), payload, binary, telemetry::traceparent(&cx), upstream, auth).await {
+                                Ok(_) => None,
+                                Err(error) => {
+                                    warn!("Failed to publish to topic {}: {}", topic, error);
+                                    Some((ErrorCode::UpstreamError, format!("unable to publish to {}", topic)))
+                                }
+                            }
+                        };
+
+                        match error {
+                            Some((code, message)) => socket.send(WsMessage::Error {
+                                topic: None,
+                                request_id: Some(request_id),
+                                code,
+                                message
+                            }).await??,
+                            None => socket.send(WsMessage::Publication { topic, request_id }).await??
+                        };
                         Ok(true)
                     })
                 } else {
@@ -224,23 +376,30 @@ impl Handler<DispatcherMessage> for Dispatcher {
                 }
             }
             DispatcherMessage::Close(socket_id) => {
-                let topics = self.subscription_table.remove_all(&socket_id);
-                let watchtower_client = self.watchtower_client.clone();
-                let http_client = self.http_client.clone();
+                let user = self.ws_table.remove(&socket_id)
+                    .and_then(|socket| socket.claims)
+                    .map(|claims| claims.sub);
+                let left_topics: Vec<String> = self.fanout.socket_topics(&socket_id)
+                    .into_iter()
+                    .filter(|topic| Self::has_presence(topic))
+                    .collect();
+                let topics = self.fanout.unregister(&socket_id);
+                let upstream = self.upstream.clone();
                 let auth = self.auth.clone();
+                let coordinator = self.coordinator.clone();
                 Box::pin(async move {
                     for topic in topics {
-                        Self::unsubscribe(topic, watchtower_client.clone(), http_client.clone(), auth.clone()).await?;
+                        coordinator.send(CoordinatorMessage::Unsubscribe(topic)).await??;
+                    }
+                    for topic in left_topics {
+                        Self::announce(topic, "leave", user.clone(), upstream.clone(), auth.clone()).await;
                     }
                     Ok(true)
                 })
             }
-            DispatcherMessage::Reset => {
-                let watchtower_client = self.watchtower_client.clone();
-                let http_client = self.http_client.clone();
-                let auth = self.auth.clone();
+            DispatcherMessage::Probe(sent_at) => {
+                metrics().mailbox_latency.observe(sent_at.elapsed().as_secs_f64());
                 Box::pin(async {
-                    Self::reset(watchtower_client, http_client, auth).await?;
                     Ok(true)
                 })
             }
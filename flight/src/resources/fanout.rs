@@ -0,0 +1,180 @@
+use actix::Addr;
+use log::warn;
+use opentelemetry::{KeyValue, trace::SpanKind};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, BTreeSet, hash_map::Entry},
+    sync::{Arc, RwLock}
+};
+use crate::{
+    resources::{SubscriptionTable, MyWs, ws::{WsMessage, Payload}},
+    types::{Event, Presence, WsFrame},
+    utils::{topic, encoding::Encoding, metrics::metrics}
+};
+
+#[derive(Clone)]
+struct Target {
+    addr: Addr<MyWs>,
+    encoding: Encoding,
+    // subject of the token of the socket
+    user: Option<String>
+}
+
+struct Routes {
+    subscription_table: SubscriptionTable,
+    targets: HashMap<String, Target>
+}
+
+/// Subscriptions and sockets of this instance, shared by the dispatcher, which keeps them up to date,
+/// and whatever receives events, so that events are delivered on the thread they arrived on.
+#[derive(Clone)]
+pub struct Fanout {
+    routes: Arc<RwLock<Routes>>
+}
+
+impl Fanout {
+    pub fn new() -> Fanout {
+        Fanout {
+            routes: Arc::new(RwLock::new(Routes {
+                subscription_table: SubscriptionTable::new(),
+                targets: HashMap::new()
+            }))
+        }
+    }
+
+    pub fn register(&self, socket_id: &str, addr: Addr<MyWs>, encoding: Encoding, user: Option<String>) {
+        self.routes.write().unwrap().targets.insert(socket_id.to_string(), Target { addr, encoding, user });
+    }
+
+    /// Forgets the socket and its subscriptions, returning the topics no other socket is subscribed to.
+    pub fn unregister(&self, socket_id: &str) -> Vec<String> {
+        let mut routes = self.routes.write().unwrap();
+        routes.targets.remove(socket_id);
+        routes.subscription_table.remove_all(socket_id)
+    }
+
+    /// Returns whether the socket is the first one subscribed to the topic.
+    pub fn subscribe(&self, socket_id: &str, topic: &str) -> bool {
+        self.routes.write().unwrap().subscription_table.insert(socket_id, topic)
+    }
+
+    /// Returns whether the socket was the last one subscribed to the topic.
+    pub fn unsubscribe(&self, socket_id: &str, topic: &str) -> bool {
+        self.routes.write().unwrap().subscription_table.remove(socket_id, topic)
+    }
+
+    /// Returns whether any socket is subscribed to exactly this topic.
+    pub fn has_subscribers(&self, topic: &str) -> bool {
+        !self.routes.read().unwrap().subscription_table.members(topic).is_empty()
+    }
+
+    pub fn is_subscribed(&self, socket_id: &str, topic: &str) -> bool {
+        self.routes.read().unwrap().subscription_table.is_subscribed(socket_id, topic)
+    }
+
+    /// Returns the sockets subscribed to exactly this topic, along with the subjects of the authenticated ones.
+    pub fn presence(&self, topic: &str) -> Presence {
+        let routes = self.routes.read().unwrap();
+        let targets: Vec<&Target> = routes.subscription_table.members(topic)
+            .into_iter()
+            .filter_map(|socket_id| routes.targets.get(socket_id))
+            .collect();
+        let users: BTreeSet<String> = targets.iter()
+            .filter_map(|target| target.user.clone())
+            .collect();
+
+        Presence {
+            count: targets.len(),
+            users: users.into_iter().collect()
+        }
+    }
+
+    pub fn socket_topics(&self, socket_id: &str) -> Vec<String> {
+        self.routes.read().unwrap().subscription_table.socket_topics(socket_id)
+    }
+
+    pub fn socket_count(&self) -> usize {
+        self.routes.read().unwrap().targets.len()
+    }
+
+    /// Returns the topics with the most sockets subscribed to exactly them, at most `limit` of them.
+    pub fn top_topics(&self, limit: usize) -> Vec<(String, usize)> {
+        let routes = self.routes.read().unwrap();
+        let mut counts: Vec<(String, usize)> = routes.subscription_table.topics()
+            .into_iter()
+            .map(|topic| {
+                let count = routes.subscription_table.members(&topic).len();
+                (topic, count)
+            })
+            .collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(limit);
+        counts
+    }
+
+    /// Delivers an event to every socket subscribed to its topic without waiting on any of them,
+    /// returning the number of sockets it was handed to.
+    pub fn broadcast(&self, event: Event) -> usize {
+        let Event { topic, payload, binary, seq, traceparent } = event;
+        metrics().events_received.inc();
+        let cx = telemetry::span("broadcast", SpanKind::Internal, &telemetry::parse(traceparent.as_deref()), vec![
+            KeyValue::new("topic", topic.to_string())
+        ]);
+        // sockets start their spans from the broadcast without holding it open
+        let trace = telemetry::detach(&cx);
+        let json = if binary {
+            None
+        } else {
+            match serde_json::from_slice::<Value>(&payload) {
+                Ok(json) => Some(json),
+                Err(error) => {
+                    warn!("Failed to parse message: {}", error);
+                    return 0;
+                }
+            }
+        };
+        let payload = match &json {
+            Some(json) => Payload::Json(json),
+            None => Payload::Binary(&payload)
+        };
+
+        let targets: Vec<Target> = {
+            let routes = self.routes.read().unwrap();
+            // presence topics would otherwise reach every pattern matching the topic they are named after
+            let socket_ids = if topic::is_presence(&topic) {
+                routes.subscription_table.members(&topic)
+            } else {
+                routes.subscription_table.get(&topic)
+            };
+            socket_ids.into_iter()
+                .filter_map(|socket_id| routes.targets.get(socket_id).cloned())
+                .collect()
+        };
+
+        // each event is encoded once per encoding in use among its sockets, and the frames are shared
+        let topic: Arc<str> = Arc::from(topic);
+        let mut frames: HashMap<Encoding, WsFrame> = HashMap::new();
+        let mut delivered = 0;
+        for target in targets {
+            let frame = match frames.entry(target.encoding) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => match MyWs::encode_event(target.encoding, &topic, seq, payload) {
+                    Ok(frame) => entry.insert(frame).clone(),
+                    Err(error) => {
+                        warn!("Failed to encode an event for topic {}: {}", topic, error);
+                        continue;
+                    }
+                }
+            };
+            if !target.addr.connected() {
+                warn!("Failed to send an event for topic {}: socket is closed", topic);
+                metrics().dropped_messages.with_label_values(&["closed"]).inc();
+                continue;
+            }
+            target.addr.do_send(WsMessage::Event { topic: topic.clone(), seq, frame, trace: trace.clone() });
+            metrics().messages_delivered.inc();
+            delivered += 1;
+        }
+        delivered
+    }
+}
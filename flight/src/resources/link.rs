@@ -0,0 +1,51 @@
+use actix::{Actor, StreamHandler};
+use actix_web::web;
+use actix_web_actors::ws;
+use log::warn;
+use crate::{
+    types::AppState,
+    utils::frame
+};
+
+/// Receiving end of the stream opened by traffic-control, delivering the events of its frames to sockets.
+pub struct LinkWs {
+    app_state: web::Data<AppState>
+}
+
+impl Actor for LinkWs {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl LinkWs {
+    pub fn new(app_state: web::Data<AppState>) -> LinkWs {
+        LinkWs {
+            app_state
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LinkWs {
+    fn handle(
+        &mut self,
+        msg: Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                ctx.pong(&msg)
+            }
+            Ok(ws::Message::Binary(bytes)) => {
+                match frame::decode_event(&bytes) {
+                    Some(event) => {
+                        self.app_state.get_ref().fanout.broadcast(event);
+                    }
+                    None => warn!("Received an invalid frame from traffic-control")
+                }
+            }
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+            }
+            _ => ()
+        };
+    }
+}
@@ -1,7 +1,23 @@
 mod subscription_table;
 mod dispatcher;
 mod ws;
+mod topic_authorizer;
+mod link;
+mod redis_listener;
+mod fanout;
+mod coordinator;
+mod outbound;
+mod upstream;
+pub mod discovery;
 
 pub use subscription_table::SubscriptionTable;
-pub use dispatcher::{Dispatcher, DispatcherMessage};
-pub use ws::MyWs;
\ No newline at end of file
+pub use dispatcher::{Dispatcher, Dispatchers, DispatcherMessage};
+pub use ws::MyWs;
+pub use link::LinkWs;
+pub use redis_listener::RedisListener;
+pub use fanout::Fanout;
+pub use outbound::Metered;
+pub use coordinator::{Coordinator, CoordinatorMessage};
+pub use upstream::Upstream;
+pub use discovery::Discovery;
+pub use topic_authorizer::{TopicAuthorizer, ClaimsAuthorizer};
\ No newline at end of file
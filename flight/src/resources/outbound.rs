@@ -0,0 +1,286 @@
+use actix::Addr;
+use actix_web::web::Bytes;
+use futures_util::stream::Stream;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    task::{Context, Poll}
+};
+use crate::{
+    resources::{MyWs, ws::WsMessage},
+    types::WsFrame,
+    utils::{env::{OutboundConfig, SlowConsumerPolicy}, metrics::metrics}
+};
+
+// frames are only written to the context of a socket while its connection has taken all but this many bytes
+const WRITE_WINDOW_BYTES: usize = 64 * 1024;
+
+/// Bytes written to the context of a socket that its connection has not taken yet.
+#[derive(Default)]
+pub struct InFlight {
+    bytes: AtomicUsize,
+    // set while frames wait in the queue for the connection to catch up
+    waiting: AtomicBool
+}
+
+impl InFlight {
+    fn has_room(&self) -> bool {
+        self.bytes.load(Ordering::Acquire) < WRITE_WINDOW_BYTES
+    }
+
+    // frame headers are not counted when writing, so the count saturates at zero
+    fn take(&self, len: usize) {
+        let _ = self.bytes.fetch_update(Ordering::AcqRel, Ordering::Acquire, |bytes| Some(bytes.saturating_sub(len)));
+    }
+}
+
+struct Queued {
+    frame: WsFrame,
+    // topic of a live event, which replies, errors and replayed events do not have
+    key: Option<Arc<str>>
+}
+
+/// Frames waiting to be written to a socket, bounded in messages and bytes. Only live events are ever
+/// dropped, so that replies, errors and replays always reach the socket.
+pub struct OutboundQueue {
+    frames: VecDeque<Queued>,
+    bytes: usize,
+    dropped: u64,
+    config: OutboundConfig,
+    in_flight: Arc<InFlight>
+}
+
+impl OutboundQueue {
+    pub fn new(config: OutboundConfig) -> OutboundQueue {
+        OutboundQueue {
+            frames: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+            config,
+            in_flight: Arc::new(InFlight::default())
+        }
+    }
+
+    pub fn in_flight(&self) -> Arc<InFlight> {
+        self.in_flight.clone()
+    }
+
+    /// Number of events this queue dropped or coalesced.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Queues a frame, applying the slow consumer policy to events when the queue is full.
+    /// Returns false when the socket is to be disconnected.
+    pub fn push(&mut self, frame: WsFrame, key: Option<Arc<str>>) -> bool {
+        let size = frame.size();
+        if key.is_some() && !self.fits(size) {
+            match self.config.policy {
+                SlowConsumerPolicy::Disconnect => return false,
+                SlowConsumerPolicy::DropNewest => {
+                    self.record_drop();
+                    return true;
+                }
+                SlowConsumerPolicy::Coalesce => {
+                    let position = self.frames.iter().position(|queued| queued.key == key);
+                    if let Some(position) = position {
+                        self.remove(position);
+                    }
+                    self.evict(size);
+                }
+                SlowConsumerPolicy::DropOldest => self.evict(size)
+            }
+        }
+
+        self.bytes += size;
+        self.frames.push_back(Queued { frame, key });
+        true
+    }
+
+    /// Pops the next frame if the connection of the socket has room for it.
+    pub fn pop_ready(&mut self) -> Option<WsFrame> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        if !self.in_flight.has_room() {
+            self.in_flight.waiting.store(true, Ordering::Release);
+            return None;
+        }
+
+        let queued = self.frames.pop_front()?;
+        let size = queued.frame.size();
+        self.bytes -= size;
+        self.in_flight.bytes.fetch_add(size, Ordering::AcqRel);
+        Some(queued.frame)
+    }
+
+    fn fits(&self, size: usize) -> bool {
+        self.frames.len() < self.config.max_messages && self.bytes + size <= self.config.max_bytes
+    }
+
+    // drops the oldest events until a frame of the given size fits
+    fn evict(&mut self, size: usize) {
+        while !self.fits(size) {
+            match self.frames.iter().position(|queued| queued.key.is_some()) {
+                Some(position) => self.remove(position),
+                None => break
+            }
+        }
+    }
+
+    fn remove(&mut self, position: usize) {
+        if let Some(queued) = self.frames.remove(position) {
+            self.bytes -= queued.frame.size();
+            self.record_drop();
+        }
+    }
+
+    fn record_drop(&mut self) {
+        self.dropped += 1;
+        metrics().dropped_messages.with_label_values(&["slow_consumer"]).inc();
+    }
+}
+
+/// Response stream of a socket, counting the bytes its connection takes so that
+/// the socket writes more of its queue once the connection has caught up.
+pub struct Metered<S> {
+    inner: Pin<Box<S>>,
+    in_flight: Arc<InFlight>,
+    addr: Addr<MyWs>
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, in_flight: Arc<InFlight>, addr: Addr<MyWs>) -> Metered<S> {
+        Metered {
+            inner: Box::pin(inner),
+            in_flight,
+            addr
+        }
+    }
+}
+
+impl<S, E> Stream for Metered<S> where S: Stream<Item = Result<Bytes, E>> {
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            self.in_flight.take(bytes.len());
+            if self.in_flight.has_room() && self.in_flight.waiting.swap(false, Ordering::AcqRel) {
+                self.addr.do_send(WsMessage::Drain);
+            }
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbound(max_messages: usize, max_bytes: usize, policy: SlowConsumerPolicy) -> OutboundQueue {
+        OutboundQueue::new(OutboundConfig { max_messages, max_bytes, policy })
+    }
+
+    fn frame(text: &str) -> WsFrame {
+        WsFrame::Text(text.into())
+    }
+
+    fn event(topic: &str) -> Option<Arc<str>> {
+        Some(topic.into())
+    }
+
+    fn drain(queue: &mut OutboundQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop_ready())
+            .map(|frame| match frame {
+                WsFrame::Text(text) => text.to_string(),
+                WsFrame::Binary(bytes) => String::from_utf8_lossy(&bytes).to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_at_capacity() {
+        let mut queue = outbound(2, 1024, SlowConsumerPolicy::DropOldest);
+        assert!(queue.push(frame("a"), event("news")));
+        assert!(queue.push(frame("b"), event("news")));
+        assert_eq!(queue.dropped(), 0);
+
+        assert!(queue.push(frame("c"), event("news")));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&mut queue), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queue_at_capacity() {
+        let mut queue = outbound(2, 1024, SlowConsumerPolicy::DropNewest);
+        assert!(queue.push(frame("a"), event("news")));
+        assert!(queue.push(frame("b"), event("news")));
+        assert!(queue.push(frame("c"), event("news")));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&mut queue), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn disconnect_once_an_event_does_not_fit() {
+        let mut queue = outbound(2, 1024, SlowConsumerPolicy::Disconnect);
+        assert!(queue.push(frame("a"), event("news")));
+        assert!(queue.push(frame("b"), event("news")));
+        assert!(!queue.push(frame("c"), event("news")));
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn coalesce_replaces_the_event_of_the_same_topic() {
+        let mut queue = outbound(2, 1024, SlowConsumerPolicy::Coalesce);
+        assert!(queue.push(frame("a"), event("news")));
+        assert!(queue.push(frame("b"), event("sport")));
+        assert!(queue.push(frame("c"), event("sport")));
+        assert!(queue.push(frame("d"), event("weather")));
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(drain(&mut queue), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn bytes_bound_the_queue_too() {
+        let mut queue = outbound(10, 4, SlowConsumerPolicy::DropOldest);
+        assert!(queue.push(frame("aa"), event("news")));
+        assert!(queue.push(frame("bb"), event("news")));
+        assert_eq!(queue.dropped(), 0);
+
+        assert!(queue.push(frame("c"), event("news")));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&mut queue), vec!["bb", "c"]);
+    }
+
+    #[test]
+    fn replies_are_never_dropped() {
+        let mut queue = outbound(1, 1024, SlowConsumerPolicy::Disconnect);
+        assert!(queue.push(frame("reply"), None));
+        assert!(queue.push(frame("error"), None));
+        assert!(!queue.push(frame("a"), event("news")));
+
+        let mut queue = outbound(1, 1024, SlowConsumerPolicy::DropOldest);
+        assert!(queue.push(frame("reply"), None));
+        assert!(queue.push(frame("a"), event("news")));
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(drain(&mut queue), vec!["reply", "a"]);
+    }
+
+    #[test]
+    fn waits_for_the_connection_to_take_the_written_bytes() {
+        let mut queue = outbound(10, 2 * WRITE_WINDOW_BYTES, SlowConsumerPolicy::DropOldest);
+        let large = "a".repeat(WRITE_WINDOW_BYTES);
+        assert!(queue.push(frame(&large), None));
+        assert!(queue.push(frame("b"), None));
+
+        assert!(queue.pop_ready().is_some());
+        assert!(queue.pop_ready().is_none());
+        let in_flight = queue.in_flight();
+        assert!(in_flight.waiting.load(Ordering::Acquire));
+
+        in_flight.take(1);
+        assert_eq!(drain(&mut queue), vec!["b"]);
+    }
+}
@@ -0,0 +1,175 @@
+use actix::clock::delay_for;
+use futures_util::{Stream, StreamExt};
+use log::{info, warn};
+use redis_async::{client::{pubsub_connect, PubsubConnection}, resp::RespValue};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::ToSocketAddrs,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    time::Duration
+};
+use crate::{
+    resources::Fanout,
+    types::{Result, Error, EVENT_CHANNEL_PREFIX},
+    utils::{env, frame, topic}
+};
+
+const RECENT_EVENTS_CAPACITY: usize = 1024;
+const MIN_RESUBSCRIBE_DELAY_MS: u64 = 100;
+const MAX_RESUBSCRIBE_DELAY_MS: u64 = 10_000;
+
+/// Remembers the last few events received so that an event matching several
+/// channels of this instance is only dispatched once.
+struct RecentEvents {
+    order: VecDeque<(String, u64)>,
+    events: HashSet<(String, u64)>
+}
+
+impl RecentEvents {
+    fn new() -> RecentEvents {
+        RecentEvents {
+            order: VecDeque::new(),
+            events: HashSet::new()
+        }
+    }
+
+    fn insert(&mut self, topic: &str, seq: u64) -> bool {
+        let key = (topic.to_string(), seq);
+        if !self.events.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > RECENT_EVENTS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.events.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Receives events published by traffic-control on Redis channels when running with the `redis` delivery mode.
+#[derive(Clone)]
+pub struct RedisListener {
+    pubsub: PubsubConnection,
+    recent: Arc<Mutex<RecentEvents>>,
+    // the listen each topic is listened to with, so that a lost channel is only subscribed to again by the current one
+    listening: Arc<Mutex<HashMap<String, u64>>>,
+    listens: Arc<AtomicU64>
+}
+
+impl RedisListener {
+    pub async fn connect() -> Result<RedisListener> {
+        let redis_info = env::get_redis_info();
+        let addr = format!("{}:{}", redis_info.host, redis_info.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::InternalError)?;
+
+        Ok(RedisListener {
+            pubsub: pubsub_connect(&addr).await?,
+            recent: Arc::new(Mutex::new(RecentEvents::new())),
+            listening: Arc::new(Mutex::new(HashMap::new())),
+            listens: Arc::new(AtomicU64::new(0))
+        })
+    }
+
+    /// Subscribes to the channel of a topic, or to a glob covering every channel a pattern may match,
+    /// and delivers the events received on it to the sockets of this instance. The connection to Redis
+    /// reconnects by itself, and a lost channel is subscribed to again until the topic is unlistened.
+    pub async fn listen(&self, topic: &str, fanout: Fanout) -> Result<()> {
+        let mut stream = self.subscribe(topic).await?;
+        let listen = self.listens.fetch_add(1, Ordering::Relaxed);
+        self.listening.lock().unwrap().insert(topic.to_string(), listen);
+
+        let listener = self.clone();
+        let topic = topic.to_string();
+        actix::spawn(async move {
+            let channel = Self::to_channel(&topic);
+            loop {
+                // the stream ends once the topic is unlistened
+                let error = loop {
+                    let event = match stream.next().await {
+                        Some(Ok(RespValue::BulkString(bytes))) => frame::decode_event(&bytes),
+                        Some(Ok(_)) => None,
+                        Some(Err(error)) => break error,
+                        None => return
+                    };
+
+                    match event {
+                        Some(event) => {
+                            let is_new = match event.seq {
+                                Some(seq) => listener.recent.lock().unwrap().insert(&event.topic, seq),
+                                None => true
+                            };
+                            if is_new {
+                                fanout.broadcast(event);
+                            }
+                        }
+                        None => warn!("Received an invalid frame on Redis channel {}", channel)
+                    }
+                };
+                warn!("Lost Redis channel {}: {:?}", channel, error);
+
+                let mut attempt = 0;
+                stream = loop {
+                    delay_for(Duration::from_millis((MIN_RESUBSCRIBE_DELAY_MS << attempt.min(8)).min(MAX_RESUBSCRIBE_DELAY_MS))).await;
+                    if listener.listening.lock().unwrap().get(&topic) != Some(&listen) {
+                        return;
+                    }
+                    match listener.subscribe(&topic).await {
+                        Ok(stream) => break stream,
+                        Err(error) => warn!("Unable to subscribe to Redis channel {} again: {:?}", channel, error)
+                    }
+                    attempt += 1;
+                };
+                info!("Subscribed to Redis channel {} again, events published in the meantime are lost", channel);
+            }
+        });
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = std::result::Result<RespValue, redis_async::error::Error>>> {
+        let channel = Self::to_channel(topic);
+        let stream = if topic::is_pattern(topic) {
+            self.pubsub.psubscribe(&channel).await?
+        } else {
+            self.pubsub.subscribe(&channel).await?
+        };
+        Ok(stream)
+    }
+
+    pub fn unlisten(&self, topic: &str) {
+        self.listening.lock().unwrap().remove(topic);
+        let channel = Self::to_channel(topic);
+        if topic::is_pattern(topic) {
+            self.pubsub.punsubscribe(channel);
+        } else {
+            self.pubsub.unsubscribe(channel);
+        }
+    }
+
+    // Redis globs cannot express a single token, so a pattern listens on a superset of its
+    // channels and the subscription table filters out the events that do not match
+    fn to_channel(topic: &str) -> String {
+        if !topic::is_pattern(topic) {
+            return format!("{}{}", EVENT_CHANNEL_PREFIX, topic);
+        }
+
+        let glob: Vec<String> = topic.split(topic::SEPARATOR).map(|token| {
+            if token == topic::SINGLE_WILDCARD || token == topic::MULTI_WILDCARD {
+                "*".to_string()
+            } else {
+                token.chars().fold(String::new(), |mut escaped, c| {
+                    if "*?[]\\".contains(c) {
+                        escaped.push('\\');
+                    }
+                    escaped.push(c);
+                    escaped
+                })
+            }
+        }).collect();
+        format!("{}{}", EVENT_CHANNEL_PREFIX, glob.join(&topic::SEPARATOR.to_string()))
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::{HashMap, BTreeSet};
+use crate::utils::topic::{SEPARATOR, SINGLE_WILDCARD, MULTI_WILDCARD};
 
 #[derive(Debug)]
 struct SubscriptionHashMap {
@@ -14,7 +15,7 @@ impl SubscriptionHashMap {
 
     fn get(&self, key: &str) -> Vec<&String> {
         match self.subscriptions.get(key) {
-            Some(set) => set.into_iter().collect(),
+            Some(set) => set.iter().collect(),
             None => vec![]
         }
     }
@@ -49,18 +50,113 @@ impl SubscriptionHashMap {
     fn remove_all(&mut self, key: &str) {
         self.subscriptions.remove(key);
     }
+
+    fn contains(&self, key: &str, value: &str) -> bool {
+        self.subscriptions.get(key).is_some_and(|set| set.contains(value))
+    }
+
+    fn values(&self) -> BTreeSet<&String> {
+        self.subscriptions.values().flatten().collect()
+    }
+}
+
+/// Indexes subscriptions by topic token so that `*` (exactly one token) and `>` (one or more
+/// trailing tokens) patterns can be matched without scanning every subscription.
+#[derive(Debug)]
+struct SubscriptionTrie {
+    children: HashMap<String, SubscriptionTrie>,
+    subscriptions: BTreeSet<String>
+}
+
+impl SubscriptionTrie {
+    fn new() -> SubscriptionTrie {
+        SubscriptionTrie {
+            children: HashMap::new(),
+            subscriptions: BTreeSet::new()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscriptions.is_empty()
+    }
+
+    fn get(&self, topic: &str) -> Vec<&String> {
+        let tokens: Vec<&str> = topic.split(SEPARATOR).collect();
+        let mut values = BTreeSet::new();
+        self.collect(&tokens, &mut values);
+        values.into_iter().collect()
+    }
+
+    fn collect<'a>(&'a self, tokens: &[&str], values: &mut BTreeSet<&'a String>) {
+        match tokens.split_first() {
+            Some((token, rest)) => {
+                if let Some(child) = self.children.get(*token) {
+                    child.collect(rest, values);
+                }
+                if let Some(child) = self.children.get(SINGLE_WILDCARD) {
+                    child.collect(rest, values);
+                }
+                if let Some(child) = self.children.get(MULTI_WILDCARD) {
+                    values.extend(child.subscriptions.iter());
+                }
+            }
+            None => values.extend(self.subscriptions.iter())
+        }
+    }
+
+    fn members(&self, topic: &str) -> Vec<&String> {
+        let mut node = self;
+        for token in topic.split(SEPARATOR) {
+            match node.children.get(token) {
+                Some(child) => node = child,
+                None => return vec![]
+            }
+        }
+        node.subscriptions.iter().collect()
+    }
+
+    fn insert(&mut self, key: &str, value: &str) -> bool {
+        let mut node = self;
+        for token in key.split(SEPARATOR) {
+            node = node.children.entry(token.to_string()).or_insert_with(SubscriptionTrie::new);
+        }
+        let row_change = node.subscriptions.is_empty();
+        node.subscriptions.insert(value.to_string());
+        row_change
+    }
+
+    fn remove(&mut self, key: &str, value: &str) -> bool {
+        let tokens: Vec<&str> = key.split(SEPARATOR).collect();
+        self.remove_tokens(&tokens, value)
+    }
+
+    fn remove_tokens(&mut self, tokens: &[&str], value: &str) -> bool {
+        match tokens.split_first() {
+            Some((token, rest)) => {
+                let mut row_change = false;
+                if let Some(child) = self.children.get_mut(*token) {
+                    row_change = child.remove_tokens(rest, value);
+                    if child.is_empty() {
+                        self.children.remove(*token);
+                    }
+                }
+                row_change
+            }
+            None => self.subscriptions.remove(value) && self.subscriptions.is_empty()
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SubscriptionTable {
-    topic_subscriptions: SubscriptionHashMap,
+    topic_subscriptions: SubscriptionTrie,
     socket_subscriptions: SubscriptionHashMap
 }
 
 impl SubscriptionTable {
     pub fn new() -> SubscriptionTable {
         SubscriptionTable {
-            topic_subscriptions: SubscriptionHashMap::new(),
+            topic_subscriptions: SubscriptionTrie::new(),
             socket_subscriptions: SubscriptionHashMap::new()   
         }
     }
@@ -69,6 +165,19 @@ impl SubscriptionTable {
         self.topic_subscriptions.get(topic)
     }
 
+    /// Returns the sockets subscribed to exactly this topic, leaving out the patterns matching it.
+    pub fn members(&self, topic: &str) -> Vec<&String> {
+        self.topic_subscriptions.members(topic)
+    }
+
+    pub fn is_subscribed(&self, socket_id: &str, topic: &str) -> bool {
+        self.socket_subscriptions.contains(socket_id, topic)
+    }
+
+    pub fn socket_topics(&self, socket_id: &str) -> Vec<String> {
+        self.socket_subscriptions.get(socket_id).into_iter().cloned().collect()
+    }
+
     pub fn insert(&mut self, socket_id: &str, topic: &str) -> bool {
         self.socket_subscriptions.insert(socket_id, topic);
         self.topic_subscriptions.insert(topic, socket_id)
@@ -79,12 +188,16 @@ impl SubscriptionTable {
         self.topic_subscriptions.remove(topic, socket_id)
     }
 
+    pub fn topics(&self) -> Vec<String> {
+        self.socket_subscriptions.values().into_iter().cloned().collect()
+    }
+
     pub fn remove_all(&mut self, socket_id: &str) -> Vec<String> {
         let mut completely_remove_topics = Vec::new();
 
         let topics_to_remove = self.socket_subscriptions.get(socket_id);
         for topic in topics_to_remove {
-            if self.topic_subscriptions.remove(&topic, socket_id) {
+            if self.topic_subscriptions.remove(topic, socket_id) {
                 completely_remove_topics.push(topic.to_string());
             }
         }
@@ -92,3 +205,84 @@ impl SubscriptionTable {
         completely_remove_topics
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(sockets: Vec<&String>) -> Vec<&str> {
+        let mut sockets: Vec<&str> = sockets.into_iter().map(String::as_str).collect();
+        sockets.sort_unstable();
+        sockets
+    }
+
+    #[test]
+    fn single_wildcard_matches_exactly_one_token() {
+        let mut table = SubscriptionTable::new();
+        table.insert("a", "orders.*");
+        assert_eq!(sorted(table.get("orders.eu")), vec!["a"]);
+        assert!(table.get("orders").is_empty());
+        assert!(table.get("orders.eu.paris").is_empty());
+    }
+
+    #[test]
+    fn multi_wildcard_matches_one_or_more_trailing_tokens() {
+        let mut table = SubscriptionTable::new();
+        table.insert("a", "chat.>");
+        assert_eq!(sorted(table.get("chat.room")), vec!["a"]);
+        assert_eq!(sorted(table.get("chat.room.1.messages")), vec!["a"]);
+        assert!(table.get("chat").is_empty());
+    }
+
+    #[test]
+    fn collects_every_matching_subscription_once() {
+        let mut table = SubscriptionTable::new();
+        table.insert("a", "orders.eu");
+        table.insert("b", "orders.*");
+        table.insert("c", "orders.>");
+        table.insert("d", "*.eu");
+        table.insert("a", "orders.*");
+        assert_eq!(sorted(table.get("orders.eu")), vec!["a", "b", "c", "d"]);
+        assert_eq!(sorted(table.members("orders.eu")), vec!["a"]);
+        assert_eq!(sorted(table.members("orders.*")), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn empty_tokens_are_tokens_of_their_own() {
+        let mut table = SubscriptionTable::new();
+        table.insert("a", "orders.");
+        table.insert("b", "orders.*");
+        assert_eq!(sorted(table.get("orders.")), vec!["a", "b"]);
+        assert!(table.get("orders").is_empty());
+    }
+
+    #[test]
+    fn reports_row_changes() {
+        let mut table = SubscriptionTable::new();
+        assert!(table.insert("a", "orders.eu"));
+        assert!(!table.insert("b", "orders.eu"));
+        assert!(!table.remove("a", "orders.eu"));
+        assert!(table.remove("b", "orders.eu"));
+        assert!(!table.remove("b", "orders.eu"));
+    }
+
+    #[test]
+    fn removing_the_last_subscription_prunes_its_branch() {
+        let mut table = SubscriptionTable::new();
+        table.insert("a", "chat.room.>");
+        table.insert("b", "chat.room.1");
+        table.insert("a", "orders.*.eu");
+
+        table.remove("a", "orders.*.eu");
+        assert!(!table.topic_subscriptions.children.contains_key("orders"));
+
+        table.remove("a", "chat.room.>");
+        let room = &table.topic_subscriptions.children["chat"].children["room"];
+        assert!(!room.children.contains_key(">"));
+        assert!(room.children.contains_key("1"));
+
+        assert_eq!(table.remove_all("b"), vec!["chat.room.1".to_string()]);
+        assert!(table.topic_subscriptions.is_empty());
+        assert!(table.socket_topics("a").is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{types::Claims, utils::topic};
+
+pub trait TopicAuthorizer: Send + Sync {
+    /// Returns the reason for rejecting the subscription when the identity may not subscribe to `topic`.
+    fn authorize_subscribe(&self, claims: Option<&Claims>, topic: &str) -> Result<(), String>;
+
+    /// Returns the reason for rejecting the event when the identity may not publish to `topic`.
+    fn authorize_publish(&self, claims: Option<&Claims>, topic: &str) -> Result<(), String>;
+}
+
+/// Authorizes topics against the glob patterns listed in the `topics` claim of the socket's token,
+/// or in the `publish` claim for events published by the socket.
+/// `{sub}` inside a pattern is replaced by the subject of the token, e.g. `user:{sub}:*`, escaped so that
+/// it only matches itself. A wildcard subscription is only allowed by a pattern matching every topic it does.
+/// Anonymous sockets only exist when authentication is disabled and are allowed everything.
+pub struct ClaimsAuthorizer;
+
+impl TopicAuthorizer for ClaimsAuthorizer {
+    fn authorize_subscribe(&self, claims: Option<&Claims>, topic: &str) -> Result<(), String> {
+        match claims {
+            Some(claims) => {
+                if Self::is_allowed(&claims.topics, &claims.sub, topic) {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not allowed to subscribe to {}", claims.sub, topic))
+                }
+            }
+            None => Ok(())
+        }
+    }
+
+    fn authorize_publish(&self, claims: Option<&Claims>, topic: &str) -> Result<(), String> {
+        match claims {
+            Some(claims) => {
+                if Self::is_allowed(&claims.publish, &claims.sub, topic) {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not allowed to publish to {}", claims.sub, topic))
+                }
+            }
+            None => Ok(())
+        }
+    }
+}
+
+impl ClaimsAuthorizer {
+    fn is_allowed(patterns: &[String], sub: &str, topic: &str) -> bool {
+        let sub = escape(sub);
+        patterns.iter().any(|pattern| glob_includes(&pattern.replace("{sub}", &sub), topic))
+    }
+}
+
+/// Escapes the characters of `text` that have a meaning in a glob pattern.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '*' || c == '?' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+enum Glob {
+    Char(char),
+    // `?`
+    One,
+    // `*`
+    Any
+}
+
+fn parse_glob(pattern: &str) -> Vec<Glob> {
+    let mut globs = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        globs.push(match c {
+            '\\' => Glob::Char(chars.next().unwrap_or('\\')),
+            '?' => Glob::One,
+            '*' => Glob::Any,
+            c => Glob::Char(c)
+        });
+    }
+    globs
+}
+
+#[derive(PartialEq)]
+enum Part {
+    Char(char),
+    // a `*` or `>` token, standing for any number of characters
+    Wildcard
+}
+
+fn parse_topic(topic: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    for (index, token) in topic.split(topic::SEPARATOR).enumerate() {
+        if index > 0 {
+            parts.push(Part::Char(topic::SEPARATOR));
+        }
+        if token == topic::SINGLE_WILDCARD || token == topic::MULTI_WILDCARD {
+            parts.push(Part::Wildcard);
+        } else {
+            parts.extend(token.chars().map(Part::Char));
+        }
+    }
+    parts
+}
+
+/// Returns true when every topic matched by `topic` is matched by `pattern`, where `*` matches any sequence
+/// of characters, `?` matches exactly one and `\` escapes the character after it.
+/// Wildcard tokens of the topic are only covered by a `*` of the pattern, which is the only one matching
+/// any number of characters, so that `news.?` allows `news.a` but not `news.*`.
+pub fn glob_includes(pattern: &str, topic: &str) -> bool {
+    let pattern = parse_glob(pattern);
+    let topic = parse_topic(topic);
+
+    // included[p][t]: the rest of the pattern from p covers the rest of the topic from t
+    let mut included = vec![vec![false; topic.len() + 1]; pattern.len() + 1];
+    included[pattern.len()][topic.len()] = true;
+    for p in (0..pattern.len()).rev() {
+        for t in (0..=topic.len()).rev() {
+            included[p][t] = match &pattern[p] {
+                Glob::Any => included[p + 1][t] || (t < topic.len() && included[p][t + 1]),
+                Glob::One => t < topic.len() && topic[t] != Part::Wildcard && included[p + 1][t + 1],
+                Glob::Char(c) => t < topic.len() && topic[t] == Part::Char(*c) && included[p + 1][t + 1]
+            };
+        }
+    }
+    included[0][0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_claims(sub: &str, topics: &[&str]) -> Claims {
+        Claims {
+            sub: sub.to_string(),
+            exp: 0,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            publish: Vec::new()
+        }
+    }
+
+    #[test]
+    fn matches_topics_against_globs() {
+        assert!(glob_includes("news", "news"));
+        assert!(glob_includes("news.?", "news.a"));
+        assert!(!glob_includes("news.?", "news.ab"));
+        assert!(glob_includes("user:*", "user:42.inbox"));
+        assert!(!glob_includes("news", "news.a"));
+    }
+
+    #[test]
+    fn wildcard_subscriptions_need_a_star() {
+        assert!(!glob_includes("news.?", "news.*"));
+        assert!(!glob_includes("news.?", "news.>"));
+        assert!(!glob_includes("news.a", "news.*"));
+        assert!(glob_includes("news.*", "news.*"));
+        assert!(glob_includes("news.*", "news.>"));
+        assert!(glob_includes("*", "*.a.>"));
+        assert!(!glob_includes("news.*.sports", "news.>"));
+    }
+
+    #[test]
+    fn stars_inside_tokens_are_literal() {
+        assert!(!glob_includes("news.?", "news.*x"));
+        assert!(glob_includes("news.??", "news.*x"));
+    }
+
+    #[test]
+    fn subjects_cannot_widen_their_grant() {
+        let authorizer = ClaimsAuthorizer;
+        let claims = token_claims("4*", &["user:{sub}:*"]);
+        assert!(authorizer.authorize_subscribe(Some(&claims), "user:4*:inbox").is_ok());
+        assert!(authorizer.authorize_subscribe(Some(&claims), "user:42:inbox").is_err());
+
+        let claims = token_claims("?", &["user:{sub}"]);
+        assert!(authorizer.authorize_subscribe(Some(&claims), "user:?").is_ok());
+        assert!(authorizer.authorize_subscribe(Some(&claims), "user:a").is_err());
+    }
+}
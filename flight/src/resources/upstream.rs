@@ -0,0 +1,213 @@
+use actix::clock::delay_for;
+use log::{info, warn};
+use std::{
+    future::Future,
+    sync::{Arc, RwLock, atomic::{AtomicUsize, Ordering}},
+    time::{Duration, Instant}
+};
+use crate::{
+    resources::Discovery,
+    utils::{env, metrics::metrics},
+    types::{Result, Error}
+};
+
+const MIN_RETRY_DELAY_MS: u64 = 50;
+const MAX_DOWN_MS: u64 = 30_000;
+// an instance failing this many requests in a row is forgotten until it is discovered again
+const MAX_FAILURES: u32 = 5;
+
+struct Instance {
+    base_url: String,
+    failures: u32,
+    // the instance is skipped until then, unless every instance is down
+    down_until: Option<Instant>
+}
+
+/// The traffic-control instances known to this instance. Traffic-control keeps all of its state in Redis,
+/// so requests are spread over the instances in turn and retried on another instance when one is unavailable.
+#[derive(Clone)]
+pub struct Upstream {
+    http_client: reqwest::Client,
+    discovery: Arc<dyn Discovery>,
+    instances: Arc<RwLock<Vec<Instance>>>,
+    next: Arc<AtomicUsize>,
+    retries: u32
+}
+
+impl Upstream {
+    pub fn new(discovery: Arc<dyn Discovery>) -> Upstream {
+        let upstream_config = env::get_upstream_config();
+        Upstream {
+            http_client: reqwest::Client::builder()
+                .timeout(Duration::from_millis(upstream_config.timeout_ms))
+                .build()
+                .unwrap_or_else(|_| reqwest::Client::new()),
+            discovery,
+            instances: Arc::new(RwLock::new(Vec::new())),
+            next: Arc::new(AtomicUsize::new(0)),
+            retries: upstream_config.retries
+        }
+    }
+
+    /// Looks up the traffic-control instances, remembering the new ones.
+    pub async fn discover(&self) -> Result<()> {
+        let discovered = self.discovery.instances().await?;
+        let mut instances = self.instances.write().unwrap();
+        for base_url in discovered {
+            if !instances.iter().any(|instance| instance.base_url == base_url) {
+                info!("Discovered traffic-control instance {}", base_url);
+                instances.push(Instance { base_url, failures: 0, down_until: None });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a request built by `request` from the base url of an instance. Requests that could not
+    /// connect are retried on another instance, and so are idempotent requests the instance failed.
+    pub async fn call<T, F, Fut>(&self, call: &str, idempotent: bool, request: F) -> Result<T>
+    where
+        F: Fn(reqwest::Client, String) -> Fut,
+        Fut: Future<Output = reqwest::Result<T>>
+    {
+        let _timer = metrics().upstream_latency.with_label_values(&[call]).start_timer();
+        let mut attempt = 0;
+        loop {
+            let base_url = match self.pick() {
+                Some(base_url) => base_url,
+                None => {
+                    self.discover().await?;
+                    self.pick().ok_or(Error::InternalError)?
+                }
+            };
+
+            let error = match request(self.http_client.clone(), base_url.to_string()).await {
+                Ok(value) => {
+                    self.record_success(&base_url);
+                    return Ok(value);
+                }
+                Err(error) => error
+            };
+
+            // rejected requests say nothing about the health of the instance
+            let unavailable = error.is_connect() || error.is_timeout() || error.status().is_some_and(|status| status.is_server_error());
+            if unavailable {
+                self.record_failure(&base_url);
+            }
+            // anything but a failed connection may have reached traffic-control, and must not be repeated
+            let retryable = error.is_connect() || (idempotent && unavailable);
+            if !retryable || attempt >= self.retries {
+                return Err(error.into());
+            }
+
+            attempt += 1;
+            metrics().upstream_retries.with_label_values(&[call]).inc();
+            warn!("Request {} to traffic-control {} failed, retrying: {}", call, base_url, error);
+            delay_for(Duration::from_millis(MIN_RETRY_DELAY_MS << attempt)).await;
+        }
+    }
+
+    // takes the next instance that is up in turn, or the one coming back the soonest when all are down
+    fn pick(&self) -> Option<String> {
+        let instances = self.instances.read().unwrap();
+        if instances.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..instances.len())
+            .map(|offset| &instances[(start + offset) % instances.len()])
+            .find(|instance| instance.down_until.is_none_or(|until| until <= now))
+            .or_else(|| instances.iter().min_by_key(|instance| instance.down_until))
+            .map(|instance| instance.base_url.to_string())
+    }
+
+    fn record_success(&self, base_url: &str) {
+        let failed = self.instances.read().unwrap().iter()
+            .any(|instance| instance.base_url == base_url && instance.failures > 0);
+        if failed {
+            if let Some(instance) = self.instances.write().unwrap().iter_mut().find(|instance| instance.base_url == base_url) {
+                instance.failures = 0;
+                instance.down_until = None;
+            }
+        }
+    }
+
+    fn record_failure(&self, base_url: &str) {
+        let mut instances = self.instances.write().unwrap();
+        if let Some(position) = instances.iter().position(|instance| instance.base_url == base_url) {
+            let instance = &mut instances[position];
+            instance.failures += 1;
+            if instance.failures >= MAX_FAILURES {
+                warn!("Forgetting traffic-control instance {} after {} failures", base_url, MAX_FAILURES);
+                instances.remove(position);
+            } else {
+                let down_ms = std::cmp::min(MIN_RETRY_DELAY_MS << instance.failures, MAX_DOWN_MS);
+                instance.down_until = Some(Instant::now() + Duration::from_millis(down_ms));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::future::{FutureExt, LocalBoxFuture};
+
+    struct NoDiscovery;
+
+    impl Discovery for NoDiscovery {
+        fn instances(&self) -> LocalBoxFuture<'_, Result<Vec<String>>> {
+            async { Ok(Vec::new()) }.boxed_local()
+        }
+    }
+
+    fn with_instances(base_urls: &[&str]) -> Upstream {
+        let upstream = Upstream::new(Arc::new(NoDiscovery));
+        upstream.instances.write().unwrap().extend(base_urls.iter().map(|base_url| Instance {
+            base_url: base_url.to_string(),
+            failures: 0,
+            down_until: None
+        }));
+        upstream
+    }
+
+    #[test]
+    fn picks_instances_in_turn() {
+        let upstream = with_instances(&["a", "b", "c"]);
+        let picked: Vec<String> = (0..4).filter_map(|_| upstream.pick()).collect();
+        assert_eq!(picked, vec!["a", "b", "c", "a"]);
+        assert_eq!(with_instances(&[]).pick(), None);
+    }
+
+    #[test]
+    fn skips_failed_instances_until_they_are_up_again() {
+        let upstream = with_instances(&["a", "b"]);
+        upstream.record_failure("a");
+        assert!((0..4).filter_map(|_| upstream.pick()).all(|base_url| base_url == "b"));
+
+        upstream.record_success("a");
+        let picked: Vec<String> = (0..2).filter_map(|_| upstream.pick()).collect();
+        assert!(picked.contains(&"a".to_string()));
+    }
+
+    #[test]
+    fn picks_the_instance_coming_back_the_soonest_when_all_are_down() {
+        let upstream = with_instances(&["a", "b"]);
+        upstream.record_failure("a");
+        upstream.record_failure("a");
+        upstream.record_failure("b");
+        assert_eq!(upstream.pick().as_deref(), Some("b"));
+        assert_eq!(upstream.pick().as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn forgets_instances_failing_too_often() {
+        let upstream = with_instances(&["a", "b"]);
+        for _ in 0..MAX_FAILURES {
+            upstream.record_failure("a");
+        }
+        assert_eq!(upstream.instances.read().unwrap().len(), 1);
+        assert!((0..2).filter_map(|_| upstream.pick()).all(|base_url| base_url == "b"));
+    }
+}
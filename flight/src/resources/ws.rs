@@ -1,16 +1,22 @@
 use actix::{
-    Actor, StreamHandler, Handler, Message,
-    prelude::AsyncContext
+    Actor, Addr, StreamHandler, Handler, Message,
+    prelude::{AsyncContext, ActorFuture, WrapFuture}
 };
-use actix_web::web;
+use actix_web::web::Bytes;
 use actix_web_actors::ws;
-use serde::Deserialize;
-use serde_json::json;
-use std::time::Duration;
+use log::warn;
+use opentelemetry::{Context, KeyValue, trace::SpanKind};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::Duration
+};
 use crate::{
-    types::AppState,
-    resources::DispatcherMessage,
-    utils::time::get_time_since_epoch
+    types::{self, Claims, HistoryEntry, WsFrame},
+    resources::{Dispatcher, DispatcherMessage, outbound::{OutboundQueue, InFlight}},
+    utils::{env, topic, frame, encoding::Encoding, time::get_time_since_epoch, rate_limit::RateLimiter}
 };
 
 const PING_TTL_SECONDS: u64 = 30;
@@ -18,27 +24,104 @@ const RUN_INTERVAL_SEC: u64 = 15;
 
 pub struct MyWs {
     socket_id: String,
+    claims: Option<Claims>,
+    encoding: Encoding,
     last_updated_timestamp: u64,
-    app_state: web::Data<AppState>
+    // live events held back per topic until the replay of missed events has been sent
+    replaying: HashMap<String, Vec<(Option<u64>, WsFrame)>>,
+    rate_limiter: RateLimiter,
+    outbound: OutboundQueue,
+    closing: bool,
+    // shard handling the commands of this socket
+    dispatcher: Addr<Dispatcher>
 }
 
 pub enum WsMessage {
-    Text(String),
+    Event {
+        topic: Arc<str>,
+        seq: Option<u64>,
+        frame: WsFrame,
+        // context of the span that broadcast the event
+        trace: Context
+    },
+    Replay {
+        topic: String,
+        events: Vec<HistoryEntry>
+    },
     Subscription {
         topic: String,
         request_id: String,
         subscribed: bool
-    }
+    },
+    Publication {
+        topic: String,
+        request_id: String
+    },
+    Error {
+        topic: Option<String>,
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String
+    },
+    /// The connection took enough of what was written to it for queued frames to be written.
+    Drain
+}
+
+/// Codes carried by the error frames sent to clients.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame is not a valid command.
+    ParseError,
+    /// The token of the socket has expired.
+    Unauthorized,
+    /// The token of the socket does not allow the command on the topic.
+    Forbidden,
+    /// The topic cannot be used with the command.
+    InvalidTopic,
+    /// Traffic-control could not be reached or failed the request.
+    UpstreamError,
+    /// The socket sent more commands than allowed.
+    RateLimited
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum WsCommand {
-    Subscribe { topic: String, request_id: String },
+    Subscribe { topic: String, request_id: String, since_seq: Option<u64> },
     Unsubscribe { topic: String, request_id: String },
+    SubscribePresence { topic: String, request_id: String },
+    UnsubscribePresence { topic: String, request_id: String },
+    Publish { topic: String, payload: Value, request_id: String },
     Ping
 }
 
+/// Payload of an event, serialized as a byte string by the encodings that have one.
+#[derive(Clone, Copy)]
+pub enum Payload<'a> {
+    Json(&'a Value),
+    Binary(&'a [u8])
+}
+
+impl Serialize for Payload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Payload::Json(value) => value.serialize(serializer),
+            Payload::Binary(bytes) => serde_bytes::Bytes::new(bytes).serialize(serializer)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EventFrame<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    payload: Payload<'a>
+}
+
 impl Message for WsMessage {
     type Result = Result<bool, std::io::Error>;
 }
@@ -49,33 +132,247 @@ impl Actor for MyWs {
     fn started(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(Duration::from_secs(RUN_INTERVAL_SEC), move |this, ctx| {
             if this.is_expired() {
-                this.app_state.get_ref().dispatcher.do_send(DispatcherMessage::Close(this.socket_id.to_string()));
+                this.dispatcher.do_send(DispatcherMessage::Close(this.socket_id.to_string()));
                 ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Normal, "ping timeout"))));
+            } else if this.is_token_expired() {
+                this.dispatcher.do_send(DispatcherMessage::Close(this.socket_id.to_string()));
+                ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Policy, "token expired"))));
             }
         });
     }
 }
 
 impl MyWs {
-    pub fn new(socket_id: String, app_state: web::Data<AppState>) -> MyWs {
+    pub fn new(socket_id: String, claims: Option<Claims>, encoding: Encoding, dispatcher: Addr<Dispatcher>) -> MyWs {
         MyWs {
             socket_id,
+            claims,
+            encoding,
             last_updated_timestamp: get_time_since_epoch().unwrap(),
-            app_state
+            replaying: HashMap::new(),
+            rate_limiter: RateLimiter::new(env::get_command_rate_limit()),
+            outbound: OutboundQueue::new(env::get_outbound_config()),
+            closing: false,
+            dispatcher
         }
     }
 
+    /// Bytes written to this socket that its connection has not taken yet, to be counted by its response stream.
+    pub fn in_flight(&self) -> Arc<InFlight> {
+        self.outbound.in_flight()
+    }
+
     pub fn is_expired(&self) -> bool {
         (self.last_updated_timestamp + PING_TTL_SECONDS) < get_time_since_epoch().unwrap()
     }
 
-    pub fn create_subscription_response(topic: String, request_id: String, subscribed: bool) -> String {
+    pub fn is_token_expired(&self) -> bool {
+        match &self.claims {
+            Some(claims) => claims.exp < get_time_since_epoch().unwrap(),
+            None => false
+        }
+    }
+
+    /// Encodes an event for the sockets using `encoding`. JSON has no byte strings,
+    /// so binary payloads are written to JSON sockets as binary event frames instead.
+    pub fn encode_event(encoding: Encoding, topic: &str, seq: Option<u64>, payload: Payload) -> types::Result<WsFrame> {
+        match (encoding, payload) {
+            (Encoding::Json, Payload::Binary(bytes)) => frame::encode_binary_event(topic, seq, bytes)
+                .map(WsFrame::Binary)
+                .ok_or(types::Error::BadRequest),
+            _ => encoding.encode(&EventFrame {
+                kind: "event",
+                topic,
+                seq,
+                payload
+            })
+        }
+    }
+
+    pub fn create_subscription_response(topic: String, request_id: String, subscribed: bool) -> Value {
         json!({
             "topic": topic,
             "subscribed": subscribed,
             "type": "response",
             "request_id": request_id
-        }).to_string()
+        })
+    }
+
+    pub fn create_publication_response(topic: String, request_id: String) -> Value {
+        json!({
+            "topic": topic,
+            "published": true,
+            "type": "response",
+            "request_id": request_id
+        })
+    }
+
+    pub fn create_error(request_id: Option<String>, code: ErrorCode, message: &str) -> Value {
+        json!({
+            "type": "error",
+            "request_id": request_id,
+            "code": code,
+            "message": message
+        })
+    }
+
+    // best effort to correlate a rejected frame with the request it was meant to be
+    fn request_id_of(encoding: Encoding, bytes: &[u8]) -> Option<String> {
+        if bytes.first() == Some(&frame::PUBLISH_FRAME) {
+            return frame::peek_request_id(bytes);
+        }
+        encoding.decode::<Value>(bytes).ok()?
+            .get("request_id")?
+            .as_str()
+            .map(|request_id| request_id.to_string())
+    }
+
+    // checks the rate limit and the token before a command is carried out
+    fn admit(&mut self, bytes: &[u8], encoding: Encoding, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if !self.rate_limiter.try_acquire() {
+            self.reply(&Self::create_error(Self::request_id_of(encoding, bytes), ErrorCode::RateLimited, "too many commands"), ctx);
+            return false;
+        }
+        if self.is_token_expired() {
+            self.reply(&Self::create_error(Self::request_id_of(encoding, bytes), ErrorCode::Unauthorized, "token expired"), ctx);
+            return false;
+        }
+        true
+    }
+
+    fn reply(&mut self, value: &Value, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.encoding.encode(value) {
+            Ok(frame) => self.send(frame, None, ctx),
+            Err(error) => warn!("Failed to encode a reply: {}", error)
+        }
+    }
+
+    // queues a frame, where events carry their topic, and writes as much of the queue as the connection allows
+    fn send(&mut self, frame: WsFrame, topic: Option<Arc<str>>, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.closing {
+            return;
+        }
+
+        let dropped = self.outbound.dropped();
+        if !self.outbound.push(frame, topic) {
+            warn!("Closing socket {}, which does not keep up with its events", self.socket_id);
+            self.closing = true;
+            self.dispatcher.do_send(DispatcherMessage::Close(self.socket_id.to_string()));
+            ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Again, "slow consumer"))));
+            return;
+        }
+        if dropped == 0 && self.outbound.dropped() > 0 {
+            warn!("Socket {} does not keep up with its events, dropping some of them", self.socket_id);
+        }
+        self.flush(ctx);
+    }
+
+    fn flush(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        while let Some(frame) = self.outbound.pop_ready() {
+            Self::write(frame, ctx);
+        }
+    }
+
+    fn write(frame: WsFrame, ctx: &mut ws::WebsocketContext<Self>) {
+        match frame {
+            WsFrame::Text(text) => ctx.text(text.as_ref()),
+            WsFrame::Binary(bytes) => ctx.binary(bytes)
+        }
+    }
+
+    fn replay_frame(&self, topic: &str, event: &HistoryEntry) -> Option<WsFrame> {
+        if !event.binary {
+            return Self::encode_event(self.encoding, topic, Some(event.seq), Payload::Json(&event.payload)).ok();
+        }
+        let payload = base64::decode(event.payload.as_str()?).ok()?;
+        Self::encode_event(self.encoding, topic, Some(event.seq), Payload::Binary(&payload)).ok()
+    }
+
+    // replies use the negotiated encoding, even to commands sent in another one
+    fn handle_command(&mut self, bytes: &[u8], encoding: Encoding, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.admit(bytes, encoding, ctx) {
+            return;
+        }
+
+        // binary publish frames cannot be mistaken for commands, which are maps in every encoding
+        if bytes.first() == Some(&frame::PUBLISH_FRAME) {
+            match frame::decode_publish(bytes) {
+                Some((request_id, topic, payload)) => {
+                    self.dispatcher.do_send(DispatcherMessage::Publish {
+                        socket_id: self.socket_id.to_string(),
+                        topic,
+                        payload,
+                        binary: true,
+                        request_id
+                    });
+                }
+                None => {
+                    self.reply(&Self::create_error(Self::request_id_of(encoding, bytes), ErrorCode::ParseError, "invalid binary publish frame"), ctx);
+                }
+            }
+            return;
+        }
+
+        match encoding.decode(bytes) {
+            Ok(WsCommand::Subscribe { topic, request_id, since_seq }) => {
+                // patterns have no history of their own, so only concrete topics are replayed
+                let since_seq = since_seq.filter(|_| !topic::is_pattern(&topic));
+                let subscribe = DispatcherMessage::Subscribe {
+                    socket_id: self.socket_id.to_string(), 
+                    topic: topic.to_string(), 
+                    request_id: request_id.to_string(),
+                    since_seq
+                };
+                if since_seq.is_some() {
+                    self.replaying.insert(topic.to_string(), Vec::new());
+                    // a dispatcher that does not know the socket never sends the replay releasing its live events
+                    ctx.spawn(self.dispatcher.send(subscribe).into_actor(self).map(move |result, act, _| {
+                        if matches!(result, Ok(Ok(false)) | Err(_)) {
+                            act.replaying.remove(&topic);
+                        }
+                    }));
+                } else {
+                    self.dispatcher.do_send(subscribe);
+                }
+            }
+            Ok(WsCommand::Unsubscribe { topic, request_id }) => {
+                self.dispatcher.do_send(DispatcherMessage::Unsubscribe {
+                    socket_id: self.socket_id.to_string(), 
+                    topic: topic.to_string(), 
+                    request_id: request_id.to_string()
+                });
+            }
+            Ok(WsCommand::SubscribePresence { topic, request_id }) => {
+                self.dispatcher.do_send(DispatcherMessage::Subscribe {
+                    socket_id: self.socket_id.to_string(), 
+                    topic: topic::to_presence(&topic), 
+                    request_id: request_id.to_string(),
+                    since_seq: None
+                });
+            }
+            Ok(WsCommand::UnsubscribePresence { topic, request_id }) => {
+                self.dispatcher.do_send(DispatcherMessage::Unsubscribe {
+                    socket_id: self.socket_id.to_string(), 
+                    topic: topic::to_presence(&topic), 
+                    request_id: request_id.to_string()
+                });
+            }
+            Ok(WsCommand::Publish { topic, payload, request_id }) => {
+                self.dispatcher.do_send(DispatcherMessage::Publish {
+                    socket_id: self.socket_id.to_string(),
+                    topic,
+                    payload: Bytes::from(payload.to_string()),
+                    binary: false,
+                    request_id
+                });
+            }
+            Ok(WsCommand::Ping) => {
+                ctx.pong(&[]);
+            }
+            Err(error) => {
+                self.reply(&Self::create_error(Self::request_id_of(encoding, bytes), ErrorCode::ParseError, &error.to_string()), ctx);
+            }
+        }
     }
 }
 
@@ -91,33 +388,16 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
             Ok(ws::Message::Ping(msg)) => {
                 ctx.pong(&msg)
             }
+            // text frames are always JSON, whatever the negotiated encoding
             Ok(ws::Message::Text(text)) => {
-                match serde_json::from_str(&text) {
-                    Ok(WsCommand::Subscribe { topic, request_id }) => {
-                        self.app_state.get_ref().dispatcher.do_send(DispatcherMessage::Subscribe {
-                            socket_id: self.socket_id.to_string(), 
-                            topic: topic.to_string(), 
-                            request_id: request_id.to_string()
-                        });
-                    }
-                    Ok(WsCommand::Unsubscribe { topic, request_id }) => {
-                        self.app_state.get_ref().dispatcher.do_send(DispatcherMessage::Unsubscribe {
-                            socket_id: self.socket_id.to_string(), 
-                            topic: topic.to_string(), 
-                            request_id: request_id.to_string()
-                        });
-                    }
-                    Ok(WsCommand::Ping) => {
-                        ctx.pong(&[]);
-                    }
-                    Err(error) => {
-                        self.app_state.get_ref().dispatcher.do_send(DispatcherMessage::Close(self.socket_id.to_string()));
-                        ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Invalid, error.to_string()))));
-                    }
-                }
+                self.handle_command(text.as_bytes(), Encoding::Json, ctx);
+            }
+            Ok(ws::Message::Binary(bytes)) => {
+                let encoding = self.encoding;
+                self.handle_command(&bytes, encoding, ctx);
             }
             Ok(ws::Message::Close(_reason)) => {
-                self.app_state.get_ref().dispatcher.do_send(DispatcherMessage::Close(self.socket_id.to_string()));
+                self.dispatcher.do_send(DispatcherMessage::Close(self.socket_id.to_string()));
                 ctx.close(Some(ws::CloseReason::from((ws::CloseCode::Normal, "closed by server"))));
             }
             _ => ()
@@ -130,9 +410,130 @@ impl Handler<WsMessage> for MyWs {
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut ws::WebsocketContext<Self>) -> Self::Result {
         match msg {
-            WsMessage::Text(text) => ctx.text(text),
-            WsMessage::Subscription { topic, request_id, subscribed } => ctx.text(Self::create_subscription_response(topic, request_id, subscribed))
+            WsMessage::Event { topic, seq, frame, trace } => {
+                let _cx = telemetry::span("deliver", SpanKind::Consumer, &trace, vec![
+                    KeyValue::new("socket_id", self.socket_id.to_string()),
+                    KeyValue::new("topic", topic.to_string())
+                ]);
+                match self.replaying.get_mut(topic.as_ref()) {
+                    Some(pending) => pending.push((seq, frame)),
+                    None => self.send(frame, Some(topic), ctx)
+                }
+            }
+            WsMessage::Replay { topic, events } => {
+                let key: Arc<str> = Arc::from(topic.as_str());
+                let mut last_seq = 0;
+                for event in events {
+                    last_seq = event.seq;
+                    // the socket asked for the replay, which is bounded by the history length, so it is never dropped
+                    match self.replay_frame(&topic, &event) {
+                        Some(frame) => self.send(frame, None, ctx),
+                        None => warn!("Failed to replay event {} of topic {}", last_seq, topic)
+                    }
+                }
+
+                // live events that were already part of the replay are dropped
+                for (seq, frame) in self.replaying.remove(&topic).unwrap_or_default() {
+                    if seq.is_none_or(|seq| seq > last_seq) {
+                        self.send(frame, Some(key.clone()), ctx);
+                    }
+                }
+            }
+            WsMessage::Subscription { topic, request_id, subscribed } => {
+                if !subscribed {
+                    self.replaying.remove(&topic);
+                }
+                self.reply(&Self::create_subscription_response(topic, request_id, subscribed), ctx)
+            }
+            WsMessage::Publication { topic, request_id } => {
+                self.reply(&Self::create_publication_response(topic, request_id), ctx)
+            }
+            WsMessage::Error { topic, request_id, code, message } => {
+                if let Some(topic) = topic {
+                    self.replaying.remove(&topic);
+                }
+                self.reply(&Self::create_error(request_id, code, &message), ctx)
+            }
+            WsMessage::Drain => {
+                self.flush(ctx)
+            }
         };
         Ok(true)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct BinaryEventFrame {
+        #[serde(rename = "type")]
+        kind: String,
+        topic: String,
+        seq: Option<u64>,
+        #[serde(with = "serde_bytes")]
+        payload: Vec<u8>
+    }
+
+    fn bytes(frame: WsFrame) -> Vec<u8> {
+        match frame {
+            WsFrame::Text(text) => text.as_bytes().to_vec(),
+            WsFrame::Binary(bytes) => bytes.to_vec()
+        }
+    }
+
+    #[test]
+    fn commands_round_trip_in_every_encoding() {
+        let command = json!({ "type": "Subscribe", "topic": "news", "request_id": "1", "since_seq": 3 });
+        for encoding in [Encoding::Json, Encoding::MsgPack, Encoding::Cbor] {
+            let frame = bytes(encoding.encode(&command).unwrap());
+            match encoding.decode::<WsCommand>(&frame).unwrap() {
+                WsCommand::Subscribe { topic, request_id, since_seq } => {
+                    assert_eq!((topic.as_str(), request_id.as_str(), since_seq), ("news", "1", Some(3)));
+                }
+                command => panic!("{:?} decoded as {:?}", encoding, command)
+            }
+        }
+    }
+
+    #[test]
+    fn msgpack_events_keep_their_field_names() {
+        let payload = json!({ "score": [1, 2] });
+        let frame = bytes(MyWs::encode_event(Encoding::MsgPack, "news", Some(4), Payload::Json(&payload)).unwrap());
+        let event: Value = rmp_serde::from_slice(&frame).unwrap();
+        assert_eq!(event, json!({ "type": "event", "topic": "news", "seq": 4, "payload": { "score": [1, 2] } }));
+    }
+
+    #[test]
+    fn binary_payloads_are_byte_strings() {
+        for encoding in [Encoding::MsgPack, Encoding::Cbor] {
+            let frame = bytes(MyWs::encode_event(encoding, "news", None, Payload::Binary(&[0, 1, 255])).unwrap());
+            let event: BinaryEventFrame = encoding.decode(&frame).unwrap();
+            assert_eq!((event.kind.as_str(), event.topic.as_str(), event.seq), ("event", "news", None));
+            assert_eq!(event.payload, vec![0, 1, 255]);
+        }
+
+        // JSON has no byte strings, so the payload is sent in a binary event frame
+        let frame = bytes(MyWs::encode_event(Encoding::Json, "news", Some(4), Payload::Binary(&[0, 1, 255])).unwrap());
+        let event = frame::decode_event(&frame).unwrap();
+        assert_eq!((event.topic.as_str(), event.seq, &event.payload[..]), ("news", Some(4), &[0, 1, 255][..]));
+    }
+
+    #[test]
+    fn rejects_truncated_msgpack_commands() {
+        let command = json!({ "type": "Unsubscribe", "topic": "news", "request_id": "1" });
+        let frame = bytes(Encoding::MsgPack.encode(&command).unwrap());
+        for len in 0..frame.len() {
+            assert!(Encoding::MsgPack.decode::<WsCommand>(&frame[..len]).is_err(), "prefix of {} bytes", len);
+        }
+        assert!(Encoding::MsgPack.decode::<WsCommand>(&frame).is_ok());
+    }
+
+    #[test]
+    fn rejects_oversized_binary_event_topics() {
+        let topic = "a".repeat(u16::MAX as usize + 1);
+        assert!(MyWs::encode_event(Encoding::Json, &topic, None, Payload::Binary(&[])).is_err());
+        assert!(MyWs::encode_event(Encoding::MsgPack, &topic, None, Payload::Binary(&[])).is_ok());
+    }
+}
@@ -1,23 +1,59 @@
-use actix_web::{web, guard, HttpResponse};
+use actix_web::{web, web::Bytes, HttpRequest, HttpResponse};
+use opentelemetry::{KeyValue, trace::SpanKind};
 
 use crate::{
-    resources::DispatcherMessage,
-    types::{Result, AppState, Event, AuthorizedReq},
+    types::{Result, AppState, Event, BatchEvent, Delivery, AuthorizedReq, SEQUENCE_HEADER, JSON_CONTENT_TYPE}
 };
 
-async fn publish_event(_: AuthorizedReq, path: web::Path<(String,)>, req_body: String, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+// traffic-control sends the events of one of its batches per request, which sequence numbers make
+// slightly larger than the batch it accepted, so this stays well above its own limit of 16 MiB
+const BATCH_PAYLOAD_LIMIT: usize = 32 * 1024 * 1024;
+
+async fn publish_event(_: AuthorizedReq, req: HttpRequest, path: web::Path<(String,)>, req_body: Bytes, app_state: web::Data<AppState>) -> Result<HttpResponse> {
     let (topic,) = path.into_inner();
-    app_state.get_ref().dispatcher.send(DispatcherMessage::Event(Event {
+    let cx = telemetry::span("publish_event", SpanKind::Server, &telemetry::extract(req.headers()), vec![KeyValue::new("topic", topic.to_string())]);
+    let seq = req.headers().get(SEQUENCE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let binary = !req.headers().get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(JSON_CONTENT_TYPE));
+
+    let delivered = app_state.get_ref().fanout.broadcast(Event {
         topic,
-        message: req_body
-    })).await??;
-    Ok(HttpResponse::Ok().finish())
+        payload: req_body,
+        binary,
+        seq,
+        traceparent: telemetry::traceparent(&cx)
+    });
+    Ok(HttpResponse::Ok().json(Delivery { delivered }))
+}
+
+async fn publish_events(_: AuthorizedReq, req: HttpRequest, req_body: Bytes, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let events: Vec<BatchEvent> = serde_json::from_slice(&req_body)?;
+    let cx = telemetry::span("publish_events", SpanKind::Server, &telemetry::extract(req.headers()), vec![KeyValue::new("events", events.len() as i64)]);
+    let traceparent = telemetry::traceparent(&cx);
+
+    let mut delivered = 0;
+    for event in events {
+        delivered += app_state.get_ref().fanout.broadcast(Event {
+            topic: event.topic,
+            payload: Bytes::from(serde_json::to_vec(&event.payload)?),
+            binary: false,
+            seq: event.seq,
+            traceparent: traceparent.clone()
+        });
+    }
+    Ok(HttpResponse::Ok().json(Delivery { delivered }))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/event/{topic:.*}")
-            .guard(guard::Header("content-type", "application/json"))
             .route(web::post().to(publish_event))
+    ).service(
+        web::resource("/events")
+            .app_data(web::PayloadConfig::new(BATCH_PAYLOAD_LIMIT))
+            .route(web::post().to(publish_events))
     );
 }
@@ -1 +1,3 @@
-pub mod event;
\ No newline at end of file
+pub mod event;
+pub mod stream;
+pub mod presence;
\ No newline at end of file
@@ -0,0 +1,16 @@
+use actix_web::{web, HttpResponse};
+
+use crate::types::{Result, AppState, AuthorizedReq};
+
+async fn get_presence(_: AuthorizedReq, path: web::Path<(String,)>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let (topic,) = path.into_inner();
+    let presence = app_state.get_ref().fanout.presence(&topic);
+    Ok(HttpResponse::Ok().json(presence))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/presence/{topic:.*}")
+            .route(web::get().to(get_presence))
+    );
+}
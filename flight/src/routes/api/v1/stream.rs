@@ -0,0 +1,18 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+
+use crate::{
+    resources::LinkWs,
+    types::{Result, AppState, AuthorizedReq},
+};
+
+async fn open_stream(_: AuthorizedReq, req: HttpRequest, stream: web::Payload, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(ws::start(LinkWs::new(app_state), &req, stream)?)
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/stream")
+            .route(web::get().to(open_stream))
+    );
+}
@@ -0,0 +1,21 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    types::{Result, AppState, AuthorizedReq},
+    utils::{env, metrics::metrics}
+};
+
+pub async fn index(_: AuthorizedReq, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let fanout = &app_state.get_ref().fanout;
+    let metrics = metrics();
+    metrics.connected_sockets.set(fanout.socket_count() as i64);
+    // only the busiest topics are exposed, so that the number of series stays bounded
+    metrics.topic_sockets.reset();
+    for (topic, count) in fanout.top_topics(env::get_metrics_top_topics()) {
+        metrics.topic_sockets.with_label_values(&[&topic]).set(count as i64);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.encode()))
+}
@@ -1,2 +1,3 @@
 pub mod api;
-pub mod ws;
\ No newline at end of file
+pub mod ws;
+pub mod metrics;
\ No newline at end of file
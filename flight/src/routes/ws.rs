@@ -4,15 +4,25 @@ use uuid::Uuid;
 
 use crate::{
     types::{AppState, Result},
-    resources::{DispatcherMessage, MyWs}
+    resources::{DispatcherMessage, MyWs, Metered},
+    utils::{token, encoding::Encoding}
 };
 
 pub async fn index(req: HttpRequest, stream: web::Payload, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let claims = token::authenticate(&req)?;
     let socket_id = Uuid::new_v4();
-    let (addr, resp) = ws::start_with_addr(MyWs::new(socket_id.to_string(), app_state.clone()), &req, stream)?;
-    app_state.get_ref().dispatcher.send(DispatcherMessage::RegisterWS {
+    let dispatcher = app_state.get_ref().dispatchers.get(&socket_id.to_string()).clone();
+    let encoding = Encoding::negotiate(&req);
+    // only the negotiated protocol is offered, so that it is the one echoed back to the client
+    let mut resp = ws::handshake_with_protocols(&req, &[encoding.protocol()])?;
+    let socket = MyWs::new(socket_id.to_string(), claims.clone(), encoding, dispatcher.clone());
+    let in_flight = socket.in_flight();
+    let (addr, stream) = ws::WebsocketContext::create_with_addr(socket, stream);
+    dispatcher.send(DispatcherMessage::RegisterWS {
         socket_id: socket_id.to_string(),
-        addr
+        addr: addr.clone(),
+        claims,
+        encoding
     }).await??;
-    Ok(resp)
+    Ok(resp.streaming(Metered::new(stream, in_flight, addr)))
 }
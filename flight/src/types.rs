@@ -1,20 +1,74 @@
-use actix::{Addr, Message};
-use serde::Deserialize;
+use actix_web::web::Bytes;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use crate::{
     error::FlockError,
-    resources::Dispatcher
+    resources::{Dispatchers, Fanout}
 };
 
 pub use crate::utils::auth::AuthorizedReq;
+pub use crate::utils::token::Claims;
 
-#[derive(Deserialize)]
+pub const SEQUENCE_HEADER: &str = "Flock-Sequence";
+pub const EVENT_CHANNEL_PREFIX: &str = "event:";
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// A frame as written to sockets, either as text or as binary.
+#[derive(Clone)]
+pub enum WsFrame {
+    Text(Arc<str>),
+    Binary(Bytes)
+}
+
+impl WsFrame {
+    /// Size of the payload of the frame in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            WsFrame::Text(text) => text.len(),
+            WsFrame::Binary(bytes) => bytes.len()
+        }
+    }
+}
+
+/// An event to deliver to sockets, whose payload is JSON unless it is `binary`.
 pub struct Event {
     pub topic: String,
-    pub message: String
+    pub payload: Bytes,
+    pub binary: bool,
+    pub seq: Option<u64>,
+    // trace context of the publish the event comes from
+    pub traceparent: Option<String>
+}
+
+/// A JSON event of a batch sent by traffic-control.
+#[derive(Deserialize)]
+pub struct BatchEvent {
+    pub topic: String,
+    pub seq: Option<u64>,
+    pub payload: serde_json::Value
+}
+
+/// Reply of the event route, with the number of local sockets the event was handed to.
+#[derive(Serialize)]
+pub struct Delivery {
+    pub delivered: usize
+}
+
+/// An event replayed from the history kept by traffic-control, where binary payloads are base64 strings.
+#[derive(Deserialize, Debug)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub payload: serde_json::Value,
+    #[serde(default)]
+    pub binary: bool
 }
 
-impl Message for Event {
-    type Result = Result<bool>;
+/// Sockets subscribed to a topic, along with the subjects of the authenticated ones.
+#[derive(Serialize)]
+pub struct Presence {
+    pub count: usize,
+    pub users: Vec<String>
 }
 
 pub type Error = FlockError;
@@ -22,5 +76,6 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub dispatcher: Addr<Dispatcher>,
+    pub dispatchers: Dispatchers,
+    pub fanout: Fanout
 }
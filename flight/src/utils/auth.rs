@@ -7,6 +7,8 @@ use crate::utils::env;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthorizedReq {
+    // set from the `IsReplicated` header, which no route acts on yet
+    #[allow(dead_code)]
     pub is_replicated: bool
 }
 
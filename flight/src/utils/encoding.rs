@@ -0,0 +1,59 @@
+use actix_web::{web::Bytes, HttpRequest};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::types::{Result, WsFrame};
+
+const PROTOCOL_HEADER: &str = "sec-websocket-protocol";
+
+/// Wire encoding of the commands and frames exchanged with a socket, negotiated through `Sec-WebSocket-Protocol`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+    Cbor
+}
+
+impl Encoding {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Encoding::Json => "flock.json",
+            Encoding::MsgPack => "flock.msgpack",
+            Encoding::Cbor => "flock.cbor"
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Encoding> {
+        match protocol {
+            "flock.json" => Some(Encoding::Json),
+            "flock.msgpack" => Some(Encoding::MsgPack),
+            "flock.cbor" => Some(Encoding::Cbor),
+            _ => None
+        }
+    }
+
+    /// Picks the first supported protocol offered by the client, in its order of preference.
+    /// Clients offering none of them get JSON.
+    pub fn negotiate(req: &HttpRequest) -> Encoding {
+        req.headers().get_all(PROTOCOL_HEADER)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(',').map(str::trim))
+            .find_map(Encoding::from_protocol)
+            .unwrap_or(Encoding::Json)
+    }
+
+    /// JSON is written as text frames, and the other encodings as binary frames.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<WsFrame> {
+        Ok(match self {
+            Encoding::Json => WsFrame::Text(serde_json::to_string(value)?.into()),
+            Encoding::MsgPack => WsFrame::Binary(Bytes::from(rmp_serde::to_vec_named(value)?)),
+            Encoding::Cbor => WsFrame::Binary(Bytes::from(serde_cbor::to_vec(value)?))
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MsgPack => rmp_serde::from_slice(bytes)?,
+            Encoding::Cbor => serde_cbor::from_slice(bytes)?
+        })
+    }
+}
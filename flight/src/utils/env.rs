@@ -5,8 +5,26 @@ const DEFAULT_PORT: u16 = 8081;
 const DEFAULT_USERNAME: &str = "admin";
 const DEFAULT_PASSWORD: &str = "password";
 
+const DEFAULT_HEARTBEAT_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_COMMAND_RATE_LIMIT: u32 = 20;
+const DEFAULT_OUTBOUND_MAX_MESSAGES: usize = 1024;
+const DEFAULT_OUTBOUND_MAX_BYTES: usize = 1024 * 1024;
+const DEFAULT_METRICS_TOP_TOPICS: usize = 10;
+const DEFAULT_UPSTREAM_RETRIES: u32 = 2;
+const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 5000;
+
+const DEFAULT_REDIS_HOST: &str = "localhost";
+const DEFAULT_REDIS_PORT: u16 = 6379;
+
+const DEFAULT_TRAFFIC_CONTROL_URLS: &str = "localhost:8080";
+const DEFAULT_TRAFFIC_CONTROL_DNS_NAME: &str = "traffic-control";
+const DEFAULT_TRAFFIC_CONTROL_DNS_PORT: u16 = 8080;
+
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_URLS: &str = "http://localhost:8088";
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_USERNAME: &str = "admin";
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_PASSWORD: &str = "password";
 
 #[derive(Serialize)]
@@ -25,12 +43,24 @@ pub fn get_instance_info() -> InstanceInfo {
     }
 }
 
+pub fn get_redis_info() -> InstanceInfo {
+    InstanceInfo {
+        host: std::env::var("REDIS_HOST").unwrap_or(DEFAULT_REDIS_HOST.to_string()),
+        port: match std::env::var("REDIS_PORT") {
+            Ok(port) => port.parse::<u16>().unwrap_or(DEFAULT_REDIS_PORT),
+            _ => DEFAULT_REDIS_PORT
+        }
+    }
+}
+
+#[cfg(feature = "watchtower")]
 pub struct WatchTowerConfig {
     pub urls: Vec<String>,
     pub username: String,
     pub password: String
 }
 
+#[cfg(feature = "watchtower")]
 pub fn get_watchtower_config() -> WatchTowerConfig {
     WatchTowerConfig {
         urls: std::env::var("WATCHTOWER_URLS").unwrap_or(DEFAULT_WATCHTOWER_URLS.to_string()).split(',').map(|item| item.to_string()).collect(),
@@ -39,6 +69,73 @@ pub fn get_watchtower_config() -> WatchTowerConfig {
     }
 }
 
+// watchtower is only the default when it is built in
+#[cfg(feature = "watchtower")]
+const DEFAULT_DISCOVERY_MODE: DiscoveryMode = DiscoveryMode::Watchtower;
+#[cfg(not(feature = "watchtower"))]
+const DEFAULT_DISCOVERY_MODE: DiscoveryMode = DiscoveryMode::Static;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiscoveryMode {
+    /// Asks watchtower for the instances.
+    #[cfg(feature = "watchtower")]
+    Watchtower,
+    /// Uses the instances listed in `TRAFFIC_CONTROL_URLS`.
+    Static,
+    /// Looks up the SRV records of `TRAFFIC_CONTROL_DNS_NAME`, or its A records on `TRAFFIC_CONTROL_DNS_PORT`.
+    Dns,
+    /// Reads the instances registered in Redis.
+    Redis
+}
+
+pub struct DiscoveryConfig {
+    pub mode: DiscoveryMode,
+    pub urls: Vec<String>,
+    pub dns_name: String,
+    pub dns_port: u16
+}
+
+pub fn get_discovery_config() -> DiscoveryConfig {
+    DiscoveryConfig {
+        mode: match std::env::var("DISCOVERY").map(|mode| mode.to_lowercase()) {
+            Ok(mode) if mode == "static" => DiscoveryMode::Static,
+            Ok(mode) if mode == "dns" => DiscoveryMode::Dns,
+            Ok(mode) if mode == "redis" => DiscoveryMode::Redis,
+            _ => DEFAULT_DISCOVERY_MODE
+        },
+        // instances are addressed as host:port, with or without a scheme
+        urls: std::env::var("TRAFFIC_CONTROL_URLS").unwrap_or(DEFAULT_TRAFFIC_CONTROL_URLS.to_string())
+            .split(',')
+            .map(|url| url.trim().trim_start_matches("http://").trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty())
+            .collect(),
+        dns_name: std::env::var("TRAFFIC_CONTROL_DNS_NAME").unwrap_or(DEFAULT_TRAFFIC_CONTROL_DNS_NAME.to_string()),
+        dns_port: match std::env::var("TRAFFIC_CONTROL_DNS_PORT") {
+            Ok(port) => port.parse::<u16>().unwrap_or(DEFAULT_TRAFFIC_CONTROL_DNS_PORT),
+            _ => DEFAULT_TRAFFIC_CONTROL_DNS_PORT
+        }
+    }
+}
+
+pub struct UpstreamConfig {
+    // attempts made on other traffic-control instances after the first one failed
+    pub retries: u32,
+    pub timeout_ms: u64
+}
+
+pub fn get_upstream_config() -> UpstreamConfig {
+    UpstreamConfig {
+        retries: match std::env::var("TRAFFIC_CONTROL_RETRIES") {
+            Ok(retries) => retries.parse::<u32>().unwrap_or(DEFAULT_UPSTREAM_RETRIES),
+            _ => DEFAULT_UPSTREAM_RETRIES
+        },
+        timeout_ms: match std::env::var("TRAFFIC_CONTROL_TIMEOUT_MS") {
+            Ok(timeout) => timeout.parse::<u64>().unwrap_or(DEFAULT_UPSTREAM_TIMEOUT_MS),
+            _ => DEFAULT_UPSTREAM_TIMEOUT_MS
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AuthInfo {
     pub username: String,
@@ -51,3 +148,98 @@ pub fn get_auth_info() -> AuthInfo {
         password: std::env::var("FLOCK_PASSWORD").unwrap_or(DEFAULT_PASSWORD.to_string()), 
     }
 }
+
+pub fn get_heartbeat_interval() -> u64 {
+    match std::env::var("HEARTBEAT_INTERVAL_SECONDS") {
+        Ok(interval) => interval.parse::<u64>().unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECONDS),
+        _ => DEFAULT_HEARTBEAT_INTERVAL_SECONDS
+    }
+}
+
+pub fn get_command_rate_limit() -> u32 {
+    match std::env::var("WS_COMMAND_RATE_LIMIT") {
+        Ok(rate) => rate.parse::<u32>().unwrap_or(DEFAULT_COMMAND_RATE_LIMIT),
+        _ => DEFAULT_COMMAND_RATE_LIMIT
+    }
+}
+
+/// What to do with the events of a socket whose outbound queue is full.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SlowConsumerPolicy {
+    /// Drops the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Drops the new event.
+    DropNewest,
+    /// Replaces the queued event of the same topic with the new one, and drops the oldest event otherwise.
+    Coalesce,
+    /// Closes the socket.
+    Disconnect
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutboundConfig {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub policy: SlowConsumerPolicy
+}
+
+pub fn get_outbound_config() -> OutboundConfig {
+    OutboundConfig {
+        max_messages: match std::env::var("WS_OUTBOUND_MAX_MESSAGES") {
+            Ok(max_messages) => max_messages.parse::<usize>().unwrap_or(DEFAULT_OUTBOUND_MAX_MESSAGES),
+            _ => DEFAULT_OUTBOUND_MAX_MESSAGES
+        },
+        max_bytes: match std::env::var("WS_OUTBOUND_MAX_BYTES") {
+            Ok(max_bytes) => max_bytes.parse::<usize>().unwrap_or(DEFAULT_OUTBOUND_MAX_BYTES),
+            _ => DEFAULT_OUTBOUND_MAX_BYTES
+        },
+        policy: match std::env::var("WS_SLOW_CONSUMER_POLICY").map(|policy| policy.to_lowercase()) {
+            Ok(policy) if policy == "drop_newest" => SlowConsumerPolicy::DropNewest,
+            Ok(policy) if policy == "coalesce" => SlowConsumerPolicy::Coalesce,
+            Ok(policy) if policy == "disconnect" => SlowConsumerPolicy::Disconnect,
+            _ => SlowConsumerPolicy::DropOldest
+        }
+    }
+}
+
+/// Number of dispatcher shards, one per available CPU by default.
+pub fn get_dispatcher_shards() -> usize {
+    let default = std::thread::available_parallelism().map_or(1, |count| count.get());
+    match std::env::var("DISPATCHER_SHARDS") {
+        Ok(shards) => shards.parse::<usize>().unwrap_or(default),
+        _ => default
+    }
+}
+
+/// Number of topics whose socket count is exposed on `/metrics`.
+pub fn get_metrics_top_topics() -> usize {
+    match std::env::var("METRICS_TOP_TOPICS") {
+        Ok(count) => count.parse::<usize>().unwrap_or(DEFAULT_METRICS_TOP_TOPICS),
+        _ => DEFAULT_METRICS_TOP_TOPICS
+    }
+}
+
+pub struct TokenConfig {
+    pub secret: Option<String>
+}
+
+pub fn get_token_config() -> TokenConfig {
+    TokenConfig {
+        secret: std::env::var("FLIGHT_TOKEN_SECRET").ok()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeliveryMode {
+    /// Traffic-control sends each event to the subscribed flight instances.
+    Http,
+    /// Flight instances receive events from the Redis channels of their topics.
+    Redis
+}
+
+pub fn get_delivery_mode() -> DeliveryMode {
+    match std::env::var("DELIVERY_MODE") {
+        Ok(mode) if mode.to_lowercase() == "redis" => DeliveryMode::Redis,
+        _ => DeliveryMode::Http
+    }
+}
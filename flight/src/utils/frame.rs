@@ -0,0 +1,200 @@
+use actix_web::web::Bytes;
+use std::convert::TryInto;
+use crate::types::Event;
+
+pub const EVENT_FRAME: u8 = 1;
+pub const BINARY_EVENT_FRAME: u8 = 2;
+pub const PUBLISH_FRAME: u8 = 3;
+// set on the kind of events framed along with their trace context
+pub const TRACE_CONTEXT_FLAG: u8 = 0x80;
+
+const HEADER_LEN: usize = 11;
+
+/// Decodes an event framed as `kind (u8) | seq (u64) | topic length (u16) | topic | payload`
+/// with integers in big endian, where a sequence number of 0 means the event has none.
+/// When the kind has `TRACE_CONTEXT_FLAG` set, the topic is followed by `traceparent length (u8) | traceparent`.
+pub fn decode_event(frame: &[u8]) -> Option<Event> {
+    let kind = frame.first()? & !TRACE_CONTEXT_FLAG;
+    if frame.len() < HEADER_LEN || (kind != EVENT_FRAME && kind != BINARY_EVENT_FRAME) {
+        return None;
+    }
+
+    let seq = u64::from_be_bytes(frame[1..9].try_into().ok()?);
+    let topic_len = u16::from_be_bytes(frame[9..11].try_into().ok()?) as usize;
+    let topic = frame.get(HEADER_LEN..HEADER_LEN + topic_len)?;
+    let mut offset = HEADER_LEN + topic_len;
+
+    let traceparent = if frame[0] & TRACE_CONTEXT_FLAG != 0 {
+        let len = *frame.get(offset)? as usize;
+        let traceparent = frame.get(offset + 1..offset + 1 + len)?;
+        offset += 1 + len;
+        Some(String::from_utf8(traceparent.to_vec()).ok()?)
+    } else {
+        None
+    };
+
+    Some(Event {
+        topic: String::from_utf8(topic.to_vec()).ok()?,
+        payload: Bytes::copy_from_slice(&frame[offset..]),
+        binary: kind == BINARY_EVENT_FRAME,
+        seq: if seq == 0 { None } else { Some(seq) },
+        traceparent
+    })
+}
+
+/// Frames a binary event for sockets in the same format traffic-control uses.
+/// Returns `None` when the topic is too long to be framed.
+pub fn encode_binary_event(topic: &str, seq: Option<u64>, payload: &[u8]) -> Option<Bytes> {
+    if topic.len() > u16::MAX as usize {
+        return None;
+    }
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + topic.len() + payload.len());
+    buf.push(BINARY_EVENT_FRAME);
+    buf.extend_from_slice(&seq.unwrap_or(0).to_be_bytes());
+    buf.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    buf.extend_from_slice(topic.as_bytes());
+    buf.extend_from_slice(payload);
+    Some(Bytes::from(buf))
+}
+
+/// Decodes a binary publish command of a socket, framed as
+/// `kind (u8) | request id length (u16) | request id | topic length (u16) | topic | payload`,
+/// into its request id, topic and payload.
+pub fn decode_publish(frame: &[u8]) -> Option<(String, String, Bytes)> {
+    if frame.first() != Some(&PUBLISH_FRAME) {
+        return None;
+    }
+
+    let (request_id, rest) = read_string(&frame[1..])?;
+    let (topic, payload) = read_string(rest)?;
+    Some((request_id, topic, Bytes::copy_from_slice(payload)))
+}
+
+/// Returns the request id of a binary publish command, as far as it can be read.
+pub fn peek_request_id(frame: &[u8]) -> Option<String> {
+    if frame.first() != Some(&PUBLISH_FRAME) {
+        return None;
+    }
+    read_string(&frame[1..]).map(|(request_id, _)| request_id)
+}
+
+// reads a string prefixed by its length as a big endian u16
+fn read_string(buf: &[u8]) -> Option<(String, &[u8])> {
+    let len = u16::from_be_bytes(buf.get(0..2)?.try_into().ok()?) as usize;
+    let value = buf.get(2..2 + len)?;
+    Some((String::from_utf8(value.to_vec()).ok()?, &buf[2 + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frames an event the way traffic-control does
+    fn event_frame(kind: u8, seq: u64, topic: &str, traceparent: Option<&str>, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if traceparent.is_some() { kind | TRACE_CONTEXT_FLAG } else { kind }];
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        frame.extend_from_slice(topic.as_bytes());
+        if let Some(traceparent) = traceparent {
+            frame.push(traceparent.len() as u8);
+            frame.extend_from_slice(traceparent.as_bytes());
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    // frames a binary publish command the way flight_client does
+    fn publish_frame(request_id: &str, topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![PUBLISH_FRAME];
+        frame.extend_from_slice(&(request_id.len() as u16).to_be_bytes());
+        frame.extend_from_slice(request_id.as_bytes());
+        frame.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        frame.extend_from_slice(topic.as_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn binary_events_round_trip() {
+        let frame = encode_binary_event("news.sport", Some(42), &[0, 1, 255]).unwrap();
+        let event = decode_event(&frame).unwrap();
+        assert_eq!(event.topic, "news.sport");
+        assert_eq!(event.seq, Some(42));
+        assert_eq!(&event.payload[..], &[0, 1, 255]);
+        assert!(event.binary);
+        assert!(event.traceparent.is_none());
+
+        let frame = encode_binary_event("news", None, &[]).unwrap();
+        let event = decode_event(&frame).unwrap();
+        assert_eq!(event.seq, None);
+        assert!(event.payload.is_empty());
+    }
+
+    #[test]
+    fn decodes_json_events_with_their_trace_context() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let event = decode_event(&event_frame(EVENT_FRAME, 7, "news", Some(traceparent), b"{}")).unwrap();
+        assert_eq!(event.topic, "news");
+        assert_eq!(event.seq, Some(7));
+        assert_eq!(&event.payload[..], b"{}");
+        assert!(!event.binary);
+        assert_eq!(event.traceparent.as_deref(), Some(traceparent));
+    }
+
+    #[test]
+    fn rejects_truncated_events() {
+        let frame = event_frame(BINARY_EVENT_FRAME, 1, "news", Some("00-trace"), &[]);
+        // the payload is empty, so every prefix lacks a part of the header or of the trace context
+        for len in 0..frame.len() {
+            assert!(decode_event(&frame[..len]).is_none(), "prefix of {} bytes", len);
+        }
+        assert!(decode_event(&frame).is_some());
+    }
+
+    #[test]
+    fn rejects_other_kinds_and_invalid_topics() {
+        assert!(decode_event(&event_frame(PUBLISH_FRAME, 1, "news", None, &[])).is_none());
+        let mut frame = event_frame(EVENT_FRAME, 1, "news", None, &[]);
+        frame[HEADER_LEN] = 0xff;
+        assert!(decode_event(&frame).is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_topics() {
+        let topic = "a".repeat(u16::MAX as usize + 1);
+        assert!(encode_binary_event(&topic, Some(1), &[]).is_none());
+
+        let topic = "a".repeat(u16::MAX as usize);
+        let event = decode_event(&encode_binary_event(&topic, Some(1), &[1]).unwrap()).unwrap();
+        assert_eq!(event.topic.len(), u16::MAX as usize);
+        assert_eq!(&event.payload[..], &[1]);
+    }
+
+    #[test]
+    fn decodes_publish_commands() {
+        let (request_id, topic, payload) = decode_publish(&publish_frame("12", "news", &[0, 1, 255])).unwrap();
+        assert_eq!(request_id, "12");
+        assert_eq!(topic, "news");
+        assert_eq!(&payload[..], &[0, 1, 255]);
+
+        let (_, _, payload) = decode_publish(&publish_frame("12", "news", &[])).unwrap();
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_publish_commands() {
+        let frame = publish_frame("12", "news", &[]);
+        for len in 0..frame.len() {
+            assert!(decode_publish(&frame[..len]).is_none(), "prefix of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn peeks_the_request_id_of_truncated_publish_commands() {
+        let frame = publish_frame("12", "news", &[1]);
+        assert_eq!(peek_request_id(&frame[..5]).as_deref(), Some("12"));
+        assert_eq!(peek_request_id(&frame[..4]), None);
+        assert_eq!(peek_request_id(&event_frame(EVENT_FRAME, 1, "12", None, &[])), None);
+    }
+}
@@ -0,0 +1,83 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder
+};
+
+/// Metrics of this instance, exposed in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub connected_sockets: IntGauge,
+    pub topic_sockets: IntGaugeVec,
+    pub subscribes: IntCounter,
+    pub unsubscribes: IntCounter,
+    pub events_received: IntCounter,
+    pub messages_delivered: IntCounter,
+    pub dropped_messages: IntCounterVec,
+    pub mailbox_latency: Histogram,
+    pub upstream_latency: HistogramVec,
+    pub upstream_retries: IntCounterVec
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new();
+    let metrics = Metrics {
+        connected_sockets: IntGauge::new("flight_connected_sockets", "Sockets connected to this instance").unwrap(),
+        topic_sockets: IntGaugeVec::new(
+            Opts::new("flight_topic_sockets", "Sockets subscribed to the topics with the most subscribers"),
+            &["topic"]
+        ).unwrap(),
+        subscribes: IntCounter::new("flight_subscribes_total", "Subscriptions made by sockets").unwrap(),
+        unsubscribes: IntCounter::new("flight_unsubscribes_total", "Subscriptions ended by sockets").unwrap(),
+        events_received: IntCounter::new("flight_events_received_total", "Events received from traffic-control").unwrap(),
+        messages_delivered: IntCounter::new("flight_messages_delivered_total", "Events handed to sockets").unwrap(),
+        dropped_messages: IntCounterVec::new(
+            Opts::new("flight_dropped_messages_total", "Events that did not reach a socket"),
+            &["reason"]
+        ).unwrap(),
+        mailbox_latency: Histogram::with_opts(
+            HistogramOpts::new("flight_dispatcher_mailbox_latency_seconds", "Time a message waits in the mailbox of a dispatcher shard")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0])
+        ).unwrap(),
+        upstream_latency: HistogramVec::new(
+            HistogramOpts::new("flight_upstream_latency_seconds", "Latency of the calls to traffic-control"),
+            &["call"]
+        ).unwrap(),
+        upstream_retries: IntCounterVec::new(
+            Opts::new("flight_upstream_retries_total", "Calls to traffic-control retried after an instance failed them"),
+            &["call"]
+        ).unwrap(),
+        registry
+    };
+
+    let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+        Box::new(metrics.connected_sockets.clone()),
+        Box::new(metrics.topic_sockets.clone()),
+        Box::new(metrics.subscribes.clone()),
+        Box::new(metrics.unsubscribes.clone()),
+        Box::new(metrics.events_received.clone()),
+        Box::new(metrics.messages_delivered.clone()),
+        Box::new(metrics.dropped_messages.clone()),
+        Box::new(metrics.mailbox_latency.clone()),
+        Box::new(metrics.upstream_latency.clone()),
+        Box::new(metrics.upstream_retries.clone())
+    ];
+    for collector in collectors {
+        metrics.registry.register(collector).unwrap();
+    }
+    metrics
+});
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    /// Encodes every metric in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(error) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::warn!("Failed to encode metrics: {}", error);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
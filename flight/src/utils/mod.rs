@@ -1,3 +1,9 @@
 pub mod time;
 pub mod env;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod token;
+pub mod topic;
+pub mod frame;
+pub mod rate_limit;
+pub mod encoding;
+pub mod metrics;
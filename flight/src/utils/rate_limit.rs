@@ -0,0 +1,78 @@
+use std::time::Instant;
+
+/// Token bucket allowing `rate` operations per second on average, with bursts of up to `rate` operations.
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn acquired(limiter: &mut RateLimiter, now: Instant, attempts: usize) -> usize {
+        (0..attempts).filter(|_| limiter.try_acquire_at(now)).count()
+    }
+
+    #[test]
+    fn allows_a_burst_of_rate_operations() {
+        let mut limiter = RateLimiter::new(3);
+        let now = limiter.last_refill;
+        assert_eq!(acquired(&mut limiter, now, 5), 3);
+    }
+
+    #[test]
+    fn refills_with_time() {
+        let mut limiter = RateLimiter::new(4);
+        let start = limiter.last_refill;
+        assert_eq!(acquired(&mut limiter, start, 4), 4);
+
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(200)));
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(250)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(250)));
+        assert_eq!(acquired(&mut limiter, start + Duration::from_millis(750), 4), 2);
+    }
+
+    #[test]
+    fn bursts_stay_bounded_after_idling() {
+        let mut limiter = RateLimiter::new(3);
+        let later = limiter.last_refill + Duration::from_secs(60);
+        assert_eq!(acquired(&mut limiter, later, 10), 3);
+    }
+
+    #[test]
+    fn a_zero_rate_allows_nothing() {
+        let mut limiter = RateLimiter::new(0);
+        let later = limiter.last_refill + Duration::from_secs(60);
+        assert_eq!(acquired(&mut limiter, later, 3), 0);
+    }
+}
@@ -2,7 +2,7 @@ use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
 
 pub fn get_time_since_epoch() -> Result<u64, SystemTimeError> {
     match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(now) => Ok(now.as_secs() as u64),
+        Ok(now) => Ok(now.as_secs()),
         Err(error) => Err(error)
     }
 }
\ No newline at end of file
@@ -0,0 +1,51 @@
+use actix_web::{web, HttpRequest};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use crate::{
+    types::{Result, Error},
+    utils::env
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: u64,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub publish: Vec<String>
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>
+}
+
+/// Verifies the HS256 token sent with a WebSocket upgrade, either as the `token`
+/// query parameter or as an `Authorization: Bearer` header.
+/// Returns `None` when no token secret is configured and sockets stay anonymous.
+pub fn authenticate(req: &HttpRequest) -> Result<Option<Claims>> {
+    let secret = match env::get_token_config().secret {
+        Some(secret) => secret,
+        None => return Ok(None)
+    };
+
+    let token = get_token(req).ok_or(Error::Unauthorized)?;
+    let data = decode::<Claims>(&token, &DecodingKey::from_secret(secret.as_bytes()), &Validation::new(Algorithm::HS256))?;
+    Ok(Some(data.claims))
+}
+
+fn get_token(req: &HttpRequest) -> Option<String> {
+    if let Ok(query) = web::Query::<TokenQuery>::from_query(req.query_string()) {
+        if let Some(token) = query.into_inner().token {
+            return Some(token);
+        }
+    }
+
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let mut iter = header.splitn(2, ' ');
+    match (iter.next(), iter.next()) {
+        (Some("Bearer"), Some(token)) => Some(token.to_string()),
+        _ => None
+    }
+}
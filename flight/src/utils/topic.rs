@@ -0,0 +1,64 @@
+pub const SEPARATOR: char = '.';
+pub const SINGLE_WILDCARD: &str = "*";
+pub const MULTI_WILDCARD: &str = ">";
+pub const PRESENCE_SUFFIX: &str = "/presence";
+
+/// Returns true when the topic contains a `*` or `>` token.
+pub fn is_pattern(topic: &str) -> bool {
+    topic.split(SEPARATOR).any(|token| token == SINGLE_WILDCARD || token == MULTI_WILDCARD)
+}
+
+/// A topic is made of non-empty tokens, and may contain `*` tokens anywhere, but `>` only as its last token.
+pub fn is_valid(topic: &str) -> bool {
+    let tokens: Vec<&str> = topic.split(SEPARATOR).collect();
+    tokens.iter().all(|token| !token.is_empty())
+        && tokens[..tokens.len() - 1].iter().all(|token| *token != MULTI_WILDCARD)
+}
+
+/// Presence topics carry the join and leave events of the topic they are named after.
+pub fn is_presence(topic: &str) -> bool {
+    topic.ends_with(PRESENCE_SUFFIX)
+}
+
+pub fn to_presence(topic: &str) -> String {
+    format!("{}{}", topic, PRESENCE_SUFFIX)
+}
+
+/// Returns the topic a presence topic is named after, or the topic itself.
+pub fn strip_presence(topic: &str) -> &str {
+    topic.strip_suffix(PRESENCE_SUFFIX).unwrap_or(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_tokens() {
+        assert!(is_valid("orders"));
+        assert!(is_valid("orders.*.eu"));
+        assert!(is_valid("chat.room.>"));
+        assert!(is_valid(">"));
+        assert!(!is_valid("chat.>.messages"));
+        assert!(!is_valid(""));
+        assert!(!is_valid("orders."));
+        assert!(!is_valid(".orders"));
+        assert!(!is_valid("orders..eu"));
+    }
+
+    #[test]
+    fn detects_patterns_by_whole_tokens() {
+        assert!(is_pattern("orders.*"));
+        assert!(is_pattern("chat.>"));
+        assert!(!is_pattern("orders.*eu"));
+        assert!(!is_pattern("chat.a>b"));
+    }
+
+    #[test]
+    fn names_presence_topics() {
+        assert_eq!(to_presence("chat.room"), "chat.room/presence");
+        assert!(is_presence("chat.room/presence"));
+        assert_eq!(strip_presence("chat.room/presence"), "chat.room");
+        assert_eq!(strip_presence("chat.room"), "chat.room");
+    }
+}
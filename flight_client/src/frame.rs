@@ -0,0 +1,102 @@
+//! Binary frames exchanged with flight, with integers in big endian.
+
+use std::convert::TryInto;
+
+const BINARY_EVENT_FRAME: u8 = 2;
+const PUBLISH_FRAME: u8 = 3;
+
+const EVENT_HEADER_LEN: usize = 11;
+
+/// Decodes an event framed as `kind (u8) | seq (u64) | topic length (u16) | topic | payload`
+/// into its topic, sequence number and payload. A sequence number of 0 means the event has none.
+pub fn decode_event(frame: &[u8]) -> Option<(String, Option<u64>, &[u8])> {
+    if frame.len() < EVENT_HEADER_LEN || frame[0] != BINARY_EVENT_FRAME {
+        return None;
+    }
+
+    let seq = u64::from_be_bytes(frame[1..9].try_into().ok()?);
+    let topic_len = u16::from_be_bytes(frame[9..11].try_into().ok()?) as usize;
+    let topic = frame.get(EVENT_HEADER_LEN..EVENT_HEADER_LEN + topic_len)?;
+    Some((
+        String::from_utf8(topic.to_vec()).ok()?,
+        if seq == 0 { None } else { Some(seq) },
+        &frame[EVENT_HEADER_LEN + topic_len..]
+    ))
+}
+
+/// Frames a binary publish command as
+/// `kind (u8) | request id length (u16) | request id | topic length (u16) | topic | payload`.
+/// Returns `None` when the request id or the topic is too long to be framed.
+pub fn encode_publish(request_id: &str, topic: &str, payload: &[u8]) -> Option<Vec<u8>> {
+    let request_id_len: u16 = request_id.len().try_into().ok()?;
+    let topic_len: u16 = topic.len().try_into().ok()?;
+
+    let mut frame = Vec::with_capacity(5 + request_id.len() + topic.len() + payload.len());
+    frame.push(PUBLISH_FRAME);
+    frame.extend_from_slice(&request_id_len.to_be_bytes());
+    frame.extend_from_slice(request_id.as_bytes());
+    frame.extend_from_slice(&topic_len.to_be_bytes());
+    frame.extend_from_slice(topic.as_bytes());
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frames an event the way flight does
+    fn event_frame(seq: u64, topic: &str, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![BINARY_EVENT_FRAME];
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        frame.extend_from_slice(topic.as_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn decodes_events() {
+        let frame = event_frame(42, "news.sport", &[0, 1, 255]);
+        assert_eq!(decode_event(&frame), Some(("news.sport".to_string(), Some(42), &[0, 1, 255][..])));
+
+        let frame = event_frame(0, "news", &[]);
+        assert_eq!(decode_event(&frame), Some(("news".to_string(), None, &[][..])));
+    }
+
+    #[test]
+    fn rejects_truncated_events() {
+        let frame = event_frame(1, "news", &[]);
+        for len in 0..frame.len() {
+            assert_eq!(decode_event(&frame[..len]), None, "prefix of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_other_kinds_and_invalid_topics() {
+        let mut frame = event_frame(1, "news", &[]);
+        frame[0] = PUBLISH_FRAME;
+        assert_eq!(decode_event(&frame), None);
+
+        let mut frame = event_frame(1, "news", &[]);
+        frame[EVENT_HEADER_LEN] = 0xff;
+        assert_eq!(decode_event(&frame), None);
+    }
+
+    #[test]
+    fn frames_publish_commands() {
+        let frame = encode_publish("12", "news", &[0, 1, 255]).unwrap();
+        assert_eq!(frame, [&[PUBLISH_FRAME, 0, 2][..], b"12", &[0, 4], b"news", &[0, 1, 255]].concat());
+    }
+
+    #[test]
+    fn rejects_oversized_publish_commands() {
+        let long = "a".repeat(u16::MAX as usize + 1);
+        assert_eq!(encode_publish(&long, "news", &[]), None);
+        assert_eq!(encode_publish("12", &long, &[]), None);
+
+        let topic = "a".repeat(u16::MAX as usize);
+        let frame = encode_publish("12", &topic, &[1]).unwrap();
+        assert_eq!(frame.len(), 1 + 2 + 2 + 2 + topic.len() + 1);
+    }
+}
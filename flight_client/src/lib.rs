@@ -1,8 +1,19 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    rc::Rc
+};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use web_sys::{ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, MessageEvent, WebSocket};
 use serde_json::json;
 
+mod frame;
+mod topic;
+
+#[cfg(feature = "native")]
+pub mod native;
+
 macro_rules! console_log {
     ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
 }
@@ -11,83 +22,504 @@ macro_rules! console_log {
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
+
+    // the global function rather than the one of `window`, which Web Workers do not have
+    #[wasm_bindgen(catch, js_name = setTimeout)]
+    fn global_set_timeout(handler: &JsValue, timeout: i32) -> Result<i32, JsValue>;
+}
+
+const MIN_RECONNECT_DELAY_MS: f64 = 500.0;
+const MAX_RECONNECT_DELAY_MS: f64 = 30_000.0;
+const REQUEST_TIMEOUT_MS: i32 = 10_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ConnectionState {
+    Connecting,
+    Open,
+    Reconnecting,
+    Closed
+}
+
+impl ConnectionState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Open => "open",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Closed => "closed"
+        }
+    }
+}
+
+// event handlers of the current socket, dropped along with it
+struct Handlers {
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(ErrorEvent)>,
+    _on_open: Closure<dyn FnMut()>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>
+}
+
+enum RequestKind {
+    Subscribe(String),
+    Unsubscribe,
+    Publish
+}
+
+struct Pending {
+    // functions resolving and rejecting the promise returned for the request,
+    // none for the subscriptions sent again after reconnecting
+    promise: Option<(js_sys::Function, js_sys::Function)>,
+    kind: RequestKind
+}
+
+struct Inner {
+    base_url: String,
+    ws: Option<WebSocket>,
+    handlers: Option<Handlers>,
+    // subscribed topics and their event callbacks, subscribed to again after reconnecting
+    subscriptions: BTreeMap<String, Option<js_sys::Function>>,
+    pending: HashMap<String, Pending>,
+    next_request_id: u64,
+    state: ConnectionState,
+    attempt: u32,
+    js_on_message: js_sys::Function,
+    js_on_open: js_sys::Function,
+    js_on_error: js_sys::Function,
+    js_on_state_change: Option<js_sys::Function>
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // the socket must not call the handlers once they are dropped
+        if let Some(ws) = self.ws.take() {
+            ws.set_onmessage(None);
+            ws.set_onerror(None);
+            ws.set_onopen(None);
+            ws.set_onclose(None);
+            let _ = ws.close();
+        }
+    }
 }
 
 #[wasm_bindgen]
 pub struct FlightClient {
-    ws: WebSocket
+    inner: Rc<RefCell<Inner>>
 }
 
 #[wasm_bindgen]
 impl FlightClient {
-    pub fn new(base_url: &str, js_on_message: &js_sys::Function, js_on_open: &js_sys::Function, js_on_error: &js_sys::Function) -> FlightClient {
-        let ws = WebSocket::new(base_url).unwrap();
-
-        // let cloned_ws = ws.clone();
-        let cloned_js_on_message = js_on_message.clone();
-        let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
-            if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
-                console_log!("message event, received Text: {:?}", txt);
-                let this = JsValue::null();
-                cloned_js_on_message.call1(&this, &txt).unwrap();
-            } else {
-                console_log!("message event, received Unknown: {:?}", e.data());
-            }
-        });
-
-        // set message event handler on WebSocket
-        ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        // forget the callback to keep it alive
-        onmessage_callback.forget();
-
-        let cloned_js_on_error = js_on_error.clone();
-        let onerror_callback = Closure::<dyn FnMut(_)>::new(move |e: ErrorEvent| {
-            console_log!("error event: {:?}", e);
-            let this = JsValue::null();
-            cloned_js_on_error.call1(&this, &e).unwrap(); 
-        });
-        ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
-        onerror_callback.forget();
-    
-        let cloned_js_on_open = js_on_open.clone();
-        let onopen_callback = Closure::<dyn FnMut()>::new(move || {
-            console_log!("socket opened");
-            let this = JsValue::null();
-            cloned_js_on_open.call0(&this).unwrap(); 
-        });
-        ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
-        onopen_callback.forget();
+    /// Opens a connection that is reopened with an exponential backoff whenever it is lost.
+    /// `js_on_state_change` is called with `"connecting"`, `"open"`, `"reconnecting"` or `"closed"`.
+    pub fn new(base_url: &str, js_on_message: &js_sys::Function, js_on_open: &js_sys::Function, js_on_error: &js_sys::Function, js_on_state_change: Option<js_sys::Function>) -> FlightClient {
+        let inner = Rc::new(RefCell::new(Inner {
+            base_url: base_url.to_string(),
+            ws: None,
+            handlers: None,
+            subscriptions: BTreeMap::new(),
+            pending: HashMap::new(),
+            next_request_id: 0,
+            state: ConnectionState::Connecting,
+            attempt: 0,
+            js_on_message: js_on_message.clone(),
+            js_on_open: js_on_open.clone(),
+            js_on_error: js_on_error.clone(),
+            js_on_state_change
+        }));
+        set_state(&inner, ConnectionState::Connecting);
+        connect(&inner);
 
         FlightClient {
-            ws
+            inner
+        }
+    }
+
+    /// Returns a promise resolved with the response of flight, or rejected with its error frame or on timeout.
+    /// Events of the topic are passed to `js_on_event` when given, and to `js_on_message` otherwise.
+    /// While disconnected, the subscription is sent once the connection is open again.
+    pub fn subscribe(&self, topic: &str, js_on_event: Option<js_sys::Function>) -> js_sys::Promise {
+        self.inner.borrow_mut().subscriptions.insert(topic.to_string(), js_on_event);
+        let (request_id, promise) = request(&self.inner, RequestKind::Subscribe(topic.to_string()));
+        if is_open(&self.inner) {
+            if let Err(error) = send(&self.inner, &subscribe_message(topic, &request_id)) {
+                settle(&self.inner, &request_id, Err(error));
+            }
         }
+        promise
     }
 
-    pub fn subscribe(&self, topic: &str) -> Result<(), JsValue> {
+    pub fn unsubscribe(&self, topic: &str) -> js_sys::Promise {
+        self.inner.borrow_mut().subscriptions.remove(topic);
+        // a closed connection holds no subscription, and the topic is not subscribed to again
+        if !is_open(&self.inner) {
+            return js_sys::Promise::resolve(&JsValue::undefined());
+        }
+
+        let (request_id, promise) = request(&self.inner, RequestKind::Unsubscribe);
         let message = json!({
             "topic": topic,
-            "type": "Subscribe",
-            "request_id": "subscribe_message",
+            "type": "Unsubscribe",
+            "request_id": request_id,
         }).to_string();
-        self.ws.send_with_str(&message)?;
-        Ok(())
+        if let Err(error) = send(&self.inner, &message) {
+            settle(&self.inner, &request_id, Err(error));
+        }
+        promise
     }
 
-    pub fn unsubscribe(&self, topic: &str) -> Result<(), JsValue> {
+    pub fn publish(&self, topic: &str, payload: &str) -> js_sys::Promise {
+        let payload: serde_json::Value = match serde_json::from_str(payload) {
+            Ok(payload) => payload,
+            Err(error) => return js_sys::Promise::reject(&JsValue::from_str(&error.to_string()))
+        };
+
+        let (request_id, promise) = request(&self.inner, RequestKind::Publish);
         let message = json!({
             "topic": topic,
-            "type": "Unsubscribe",
-            "request_id": "unsubscribe_message",
+            "type": "Publish",
+            "payload": payload,
+            "request_id": request_id,
         }).to_string();
-        self.ws.send_with_str(&message)?;
-        Ok(())
+        if let Err(error) = send(&self.inner, &message) {
+            settle(&self.inner, &request_id, Err(error));
+        }
+        promise
+    }
+
+    /// Publishes a binary payload, which subscribers receive as an `ArrayBuffer`.
+    pub fn publish_binary(&self, topic: &str, payload: &[u8]) -> js_sys::Promise {
+        let (request_id, promise) = request(&self.inner, RequestKind::Publish);
+        let sent = match frame::encode_publish(&request_id, topic, payload) {
+            Some(frame) => match &self.inner.borrow().ws {
+                Some(ws) => ws.send_with_u8_array(&frame),
+                None => Err(JsValue::from_str("not connected"))
+            },
+            None => Err(JsValue::from_str("topic too long"))
+        };
+        if let Err(error) = sent {
+            settle(&self.inner, &request_id, Err(error));
+        }
+        promise
     }
 
     pub fn ping(&self) -> Result<(), JsValue> {
         let message = json!({
             "type": "Ping",
         }).to_string();
-        self.ws.send_with_str(&message)?;
+        send(&self.inner, &message)
+    }
+
+    /// Closes the connection for good.
+    pub fn close(&self) -> Result<(), JsValue> {
+        let ws = self.inner.borrow_mut().ws.take();
+        set_state(&self.inner, ConnectionState::Closed);
+        if let Some(ws) = ws {
+            ws.close()?;
+        }
         Ok(())
     }
 }
+
+fn subscribe_message(topic: &str, request_id: &str) -> String {
+    json!({
+        "topic": topic,
+        "type": "Subscribe",
+        "request_id": request_id,
+    }).to_string()
+}
+
+/// Registers a request under a new id, returning the id and the promise settled by its response.
+fn request(inner: &Rc<RefCell<Inner>>, kind: RequestKind) -> (String, js_sys::Promise) {
+    let mut functions = None;
+    let promise = js_sys::Promise::new(&mut |resolve, reject| {
+        functions = Some((resolve, reject));
+    });
+    (register(inner, kind, functions), promise)
+}
+
+/// Registers a request under a new id, so that its response is not passed to `js_on_message`, and expires it on timeout.
+fn register(inner: &Rc<RefCell<Inner>>, kind: RequestKind, promise: Option<(js_sys::Function, js_sys::Function)>) -> String {
+    let request_id = {
+        let mut inner = inner.borrow_mut();
+        inner.next_request_id += 1;
+        let request_id = inner.next_request_id.to_string();
+        inner.pending.insert(request_id.to_string(), Pending { promise, kind });
+        request_id
+    };
+
+    let weak = Rc::downgrade(inner);
+    let timeout_request_id = request_id.to_string();
+    let scheduled = set_timeout(REQUEST_TIMEOUT_MS, move || {
+        if let Some(inner) = weak.upgrade() {
+            // a subscription waiting for the connection is kept, and sent again once the connection is open
+            let forget = is_open(&inner);
+            finish(&inner, &timeout_request_id, Err(JsValue::from_str("request timed out")), forget);
+        }
+    });
+    if let Err(error) = scheduled {
+        console_log!("unable to schedule request timeout: {:?}", error);
+    }
+    request_id
+}
+
+/// Settles the promise of a request unless it already was, forgetting the subscription of a failed subscribe.
+fn settle(inner: &Rc<RefCell<Inner>>, request_id: &str, result: Result<JsValue, JsValue>) {
+    finish(inner, request_id, result, true);
+}
+
+fn finish(inner: &Rc<RefCell<Inner>>, request_id: &str, result: Result<JsValue, JsValue>, forget: bool) {
+    let pending = match inner.borrow_mut().pending.remove(request_id) {
+        Some(pending) => pending,
+        None => return
+    };
+
+    if let (Err(error), RequestKind::Subscribe(topic)) = (&result, &pending.kind) {
+        if forget {
+            inner.borrow_mut().subscriptions.remove(topic);
+        }
+        if pending.promise.is_none() {
+            console_log!("unable to subscribe to {} again: {:?}", topic, error);
+        }
+    }
+
+    let (resolve, reject) = match pending.promise {
+        Some(promise) => promise,
+        None => return
+    };
+    let this = JsValue::null();
+    let settled = match result {
+        Ok(value) => resolve.call1(&this, &value),
+        Err(error) => reject.call1(&this, &error)
+    };
+    if let Err(error) = settled {
+        console_log!("promise callback failed: {:?}", error);
+    }
+}
+
+fn handle_message(inner: &Rc<RefCell<Inner>>, txt: js_sys::JsString) {
+    let text = String::from(&txt);
+    let frame: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+    let object = || js_sys::JSON::parse(&text).unwrap_or_else(|_| txt.clone().into());
+
+    match frame["type"].as_str() {
+        Some("event") => {
+            let topic = frame["topic"].as_str().unwrap_or_default();
+            if dispatch_event(inner, topic, object) {
+                return;
+            }
+        }
+        Some(kind @ "response") | Some(kind @ "error") => {
+            let request_id = frame["request_id"].as_str().unwrap_or_default();
+            if inner.borrow().pending.contains_key(request_id) {
+                let result = if kind == "response" { Ok(object()) } else { Err(object()) };
+                settle(inner, request_id, result);
+                return;
+            }
+        }
+        _ => ()
+    }
+
+    let callback = inner.borrow().js_on_message.clone();
+    let this = JsValue::null();
+    callback.call1(&this, &txt).unwrap();
+}
+
+/// Passes a binary event to callbacks as `{ type: "event", topic, seq, payload }` with an `ArrayBuffer` payload.
+fn handle_binary(inner: &Rc<RefCell<Inner>>, buffer: js_sys::ArrayBuffer) {
+    let bytes = js_sys::Uint8Array::new(&buffer).to_vec();
+    let (topic, seq, payload) = match frame::decode_event(&bytes) {
+        Some(event) => event,
+        None => {
+            console_log!("invalid binary frame of {} bytes", bytes.len());
+            return;
+        }
+    };
+
+    let event = || {
+        let event = js_sys::Object::new();
+        let payload = js_sys::Uint8Array::from(payload).buffer();
+        let _ = js_sys::Reflect::set(&event, &JsValue::from_str("type"), &JsValue::from_str("event"));
+        let _ = js_sys::Reflect::set(&event, &JsValue::from_str("topic"), &JsValue::from_str(&topic));
+        if let Some(seq) = seq {
+            let _ = js_sys::Reflect::set(&event, &JsValue::from_str("seq"), &JsValue::from_f64(seq as f64));
+        }
+        let _ = js_sys::Reflect::set(&event, &JsValue::from_str("payload"), &payload);
+        JsValue::from(event)
+    };
+    if !dispatch_event(inner, &topic, event) {
+        let callback = inner.borrow().js_on_message.clone();
+        let this = JsValue::null();
+        callback.call1(&this, &event()).unwrap();
+    }
+}
+
+/// Passes an event to the callbacks of the subscriptions matching its topic, returning whether there was any.
+fn dispatch_event(inner: &Rc<RefCell<Inner>>, topic: &str, event: impl FnOnce() -> JsValue) -> bool {
+    let callbacks: Vec<js_sys::Function> = inner.borrow().subscriptions.iter()
+        .filter(|(pattern, _)| topic::matches(pattern, topic))
+        .filter_map(|(_, callback)| callback.clone())
+        .collect();
+    if callbacks.is_empty() {
+        return false;
+    }
+
+    let event = event();
+    let this = JsValue::null();
+    for callback in callbacks {
+        if let Err(error) = callback.call1(&this, &event) {
+            console_log!("event callback failed: {:?}", error);
+        }
+    }
+    true
+}
+
+fn set_timeout(delay_ms: i32, callback: impl FnOnce() + 'static) -> Result<i32, JsValue> {
+    global_set_timeout(&Closure::once_into_js(callback), delay_ms)
+}
+
+fn is_open(inner: &Rc<RefCell<Inner>>) -> bool {
+    let inner = inner.borrow();
+    inner.ws.is_some() && inner.state == ConnectionState::Open
+}
+
+fn send(inner: &Rc<RefCell<Inner>>, message: &str) -> Result<(), JsValue> {
+    match &inner.borrow().ws {
+        Some(ws) => ws.send_with_str(message),
+        None => Err(JsValue::from_str("not connected"))
+    }
+}
+
+fn set_state(inner: &Rc<RefCell<Inner>>, state: ConnectionState) {
+    // the callback is cloned out so that it may call back into the client
+    let callback = {
+        let mut inner = inner.borrow_mut();
+        inner.state = state;
+        inner.js_on_state_change.clone()
+    };
+    if let Some(callback) = callback {
+        let this = JsValue::null();
+        if let Err(error) = callback.call1(&this, &JsValue::from_str(state.as_str())) {
+            console_log!("state change callback failed: {:?}", error);
+        }
+    }
+}
+
+fn connect(inner: &Rc<RefCell<Inner>>) {
+    let base_url = inner.borrow().base_url.to_string();
+    let ws = match WebSocket::new(&base_url) {
+        Ok(ws) => ws,
+        Err(error) => {
+            console_log!("unable to open socket: {:?}", error);
+            schedule_reconnect(inner);
+            return;
+        }
+    };
+    // binary events are decoded synchronously, which a Blob would not allow
+    ws.set_binary_type(BinaryType::Arraybuffer);
+
+    let weak = Rc::downgrade(inner);
+    let on_message = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
+        let inner = match weak.upgrade() {
+            Some(inner) => inner,
+            None => return
+        };
+        if let Ok(txt) = e.data().dyn_into::<js_sys::JsString>() {
+            handle_message(&inner, txt);
+        } else if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+            handle_binary(&inner, buffer);
+        } else {
+            console_log!("message event, received Unknown: {:?}", e.data());
+        }
+    });
+    ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+    let weak = Rc::downgrade(inner);
+    let on_error = Closure::<dyn FnMut(_)>::new(move |e: ErrorEvent| {
+        console_log!("error event: {:?}", e);
+        if let Some(inner) = weak.upgrade() {
+            let callback = inner.borrow().js_on_error.clone();
+            let this = JsValue::null();
+            callback.call1(&this, &e).unwrap();
+        }
+    });
+    ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+    let weak = Rc::downgrade(inner);
+    let on_open = Closure::<dyn FnMut()>::new(move || {
+        console_log!("socket opened");
+        if let Some(inner) = weak.upgrade() {
+            inner.borrow_mut().attempt = 0;
+            set_state(&inner, ConnectionState::Open);
+
+            // subscriptions still waiting for a response keep their request, and so their promise
+            let subscriptions: Vec<(String, Option<String>)> = {
+                let inner = inner.borrow();
+                inner.subscriptions.keys().map(|topic| {
+                    let request_id = inner.pending.iter()
+                        .find(|(_, pending)| matches!(&pending.kind, RequestKind::Subscribe(pending_topic) if pending_topic == topic))
+                        .map(|(request_id, _)| request_id.to_string());
+                    (topic.to_string(), request_id)
+                }).collect()
+            };
+            for (topic, request_id) in subscriptions {
+                let request_id = match request_id {
+                    Some(request_id) => request_id,
+                    None => register(&inner, RequestKind::Subscribe(topic.to_string()), None)
+                };
+                if let Err(error) = send(&inner, &subscribe_message(&topic, &request_id)) {
+                    console_log!("unable to subscribe to {}: {:?}", topic, error);
+                }
+            }
+
+            let callback = inner.borrow().js_on_open.clone();
+            let this = JsValue::null();
+            callback.call0(&this).unwrap();
+        }
+    });
+    ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+
+    let weak = Rc::downgrade(inner);
+    let on_close = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+        console_log!("socket closed: {} {}", e.code(), e.reason());
+        if let Some(inner) = weak.upgrade() {
+            if inner.borrow().state != ConnectionState::Closed {
+                schedule_reconnect(&inner);
+            }
+        }
+    });
+    ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+    let mut inner = inner.borrow_mut();
+    inner.ws = Some(ws);
+    inner.handlers = Some(Handlers {
+        _on_message: on_message,
+        _on_error: on_error,
+        _on_open: on_open,
+        _on_close: on_close
+    });
+}
+
+fn schedule_reconnect(inner: &Rc<RefCell<Inner>>) {
+    let attempt = {
+        let mut inner = inner.borrow_mut();
+        inner.ws = None;
+        inner.attempt += 1;
+        inner.attempt
+    };
+    set_state(inner, ConnectionState::Reconnecting);
+
+    // jitter keeps clients that lost the same server from reconnecting all at once
+    let backoff = (MIN_RECONNECT_DELAY_MS * 2f64.powi(attempt.min(16) as i32 - 1)).min(MAX_RECONNECT_DELAY_MS);
+    let delay = backoff / 2.0 + js_sys::Math::random() * backoff / 2.0;
+
+    let weak = Rc::downgrade(inner);
+    let scheduled = set_timeout(delay as i32, move || {
+        if let Some(inner) = weak.upgrade() {
+            if inner.borrow().state == ConnectionState::Reconnecting {
+                connect(&inner);
+            }
+        }
+    });
+    if let Err(error) = scheduled {
+        console_log!("unable to schedule reconnect: {:?}", error);
+    }
+}
@@ -0,0 +1,54 @@
+use log::error;
+
+#[derive(Debug, PartialEq)]
+pub enum FlightClientError {
+    /// The connection to flight could not be opened or was lost.
+    ConnectionClosed,
+    /// Flight did not answer the request in time.
+    Timeout,
+    /// Flight rejected the request with an error frame.
+    Rejected { code: String, message: String },
+    /// The client already holds a subscription to the topic.
+    AlreadySubscribed,
+    InternalError
+}
+
+impl std::fmt::Display for FlightClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FlightClientError::ConnectionClosed => write!(f, "Connection closed"),
+            FlightClientError::Timeout => write!(f, "Request timed out"),
+            FlightClientError::Rejected { code, message } => write!(f, "{}: {}", code, message),
+            FlightClientError::AlreadySubscribed => write!(f, "Already subscribed"),
+            FlightClientError::InternalError => write!(f, "Something went wrong")
+        }
+    }
+}
+
+impl std::error::Error for FlightClientError {}
+
+impl From<tokio_tungstenite::tungstenite::Error> for FlightClientError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        error!("WebSocket Error: {:?}", error);
+        FlightClientError::ConnectionClosed
+    }
+}
+
+impl From<serde_json::Error> for FlightClientError {
+    fn from(error: serde_json::Error) -> Self {
+        error!("Serde Error: {:?}", error);
+        FlightClientError::InternalError
+    }
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for FlightClientError {
+    fn from(_: tokio::sync::oneshot::error::RecvError) -> Self {
+        FlightClientError::ConnectionClosed
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for FlightClientError {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        FlightClientError::Timeout
+    }
+}
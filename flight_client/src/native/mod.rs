@@ -0,0 +1,265 @@
+//! Tokio based client for Rust services, enabled with the `native` feature.
+use futures_util::{SinkExt, Stream, StreamExt};
+use log::warn;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use crate::{frame, topic};
+
+mod error;
+mod types;
+
+pub use types::{Event, Payload, Result, Error};
+use types::Frame;
+
+const PING_INTERVAL_SEC: u64 = 15;
+const REQUEST_TIMEOUT_SEC: u64 = 10;
+
+enum Action {
+    Subscribe { topic: String, sender: mpsc::UnboundedSender<Event> },
+    Unsubscribe { topic: String },
+    Publish
+}
+
+struct Request {
+    request_id: String,
+    frame: String,
+    responder: oneshot::Sender<Result<()>>,
+    action: Action
+}
+
+// what to do with the subscriptions once flight answers a request
+enum PendingAction {
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish
+}
+
+struct PendingRequest {
+    responder: oneshot::Sender<Result<()>>,
+    action: PendingAction
+}
+
+/// Connection to a flight instance. The socket is served by a background task that keeps it alive with pings
+/// and is closed once the client is dropped.
+pub struct FlightClient {
+    requests: mpsc::UnboundedSender<Request>,
+    next_request_id: AtomicU64
+}
+
+impl FlightClient {
+    /// Connects to the WebSocket route of flight, e.g. `ws://localhost:8081/ws?token=[your_token]`.
+    pub async fn connect(url: &str) -> Result<FlightClient> {
+        let (ws, _) = connect_async(url).await?;
+        let (requests, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(ws, receiver));
+        Ok(FlightClient {
+            requests,
+            next_request_id: AtomicU64::new(0)
+        })
+    }
+
+    pub async fn subscribe(&self, topic: &str) -> Result<Subscription> {
+        self.subscribe_since(topic, None).await
+    }
+
+    /// Subscribes to the topic, replaying the retained events published after `since_seq` first.
+    /// Fails with `Error::AlreadySubscribed` while another subscription to the topic is held.
+    pub async fn subscribe_since(&self, topic: &str, since_seq: Option<u64>) -> Result<Subscription> {
+        let request_id = self.request_id();
+        let mut frame = json!({
+            "type": "Subscribe",
+            "topic": topic,
+            "request_id": request_id
+        });
+        if let Some(since_seq) = since_seq {
+            frame["since_seq"] = json!(since_seq);
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.request(request_id, frame, Action::Subscribe { topic: topic.to_string(), sender }).await?;
+        Ok(Subscription {
+            topic: topic.to_string(),
+            receiver
+        })
+    }
+
+    /// Subscribes to the join and leave events of the topic.
+    pub async fn subscribe_presence(&self, topic: &str) -> Result<Subscription> {
+        let request_id = self.request_id();
+        let frame = json!({
+            "type": "SubscribePresence",
+            "topic": topic,
+            "request_id": request_id
+        });
+
+        let presence_topic = format!("{}/presence", topic);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.request(request_id, frame, Action::Subscribe { topic: presence_topic.to_string(), sender }).await?;
+        Ok(Subscription {
+            topic: presence_topic,
+            receiver
+        })
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<()> {
+        let request_id = self.request_id();
+        let frame = json!({
+            "type": "Unsubscribe",
+            "topic": topic,
+            "request_id": request_id
+        });
+        self.request(request_id, frame, Action::Unsubscribe { topic: topic.to_string() }).await
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &Value) -> Result<()> {
+        let request_id = self.request_id();
+        let frame = json!({
+            "type": "Publish",
+            "topic": topic,
+            "payload": payload,
+            "request_id": request_id
+        });
+        self.request(request_id, frame, Action::Publish).await
+    }
+
+    fn request_id(&self) -> String {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed).to_string()
+    }
+
+    async fn request(&self, request_id: String, frame: Value, action: Action) -> Result<()> {
+        let (responder, response) = oneshot::channel();
+        self.requests.send(Request {
+            request_id,
+            frame: frame.to_string(),
+            responder,
+            action
+        }).map_err(|_| Error::ConnectionClosed)?;
+        time::timeout(Duration::from_secs(REQUEST_TIMEOUT_SEC), response).await??
+    }
+}
+
+/// Stream of the events of a subscription. It ends when the topic is unsubscribed or the connection is lost.
+/// Dropping it stops the delivery of events but does not unsubscribe from the topic.
+pub struct Subscription {
+    topic: String,
+    receiver: mpsc::UnboundedReceiver<Event>
+}
+
+impl Subscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+async fn run(ws: WebSocketStream<MaybeTlsStream<TcpStream>>, mut requests: mpsc::UnboundedReceiver<Request>) {
+    let (mut sink, mut stream) = ws.split();
+    let mut subscriptions: HashMap<String, mpsc::UnboundedSender<Event>> = HashMap::new();
+    let mut pending: HashMap<String, PendingRequest> = HashMap::new();
+    let mut ping = time::interval(Duration::from_secs(PING_INTERVAL_SEC));
+
+    loop {
+        tokio::select! {
+            request = requests.recv() => match request {
+                Some(request) => {
+                    // events may arrive before the response, so the subscription is registered right away
+                    let action = match request.action {
+                        Action::Subscribe { topic, sender } => {
+                            // a subscription whose stream was dropped may be replaced
+                            if subscriptions.get(&topic).is_some_and(|sender| !sender.is_closed()) {
+                                let _ = request.responder.send(Err(Error::AlreadySubscribed));
+                                continue;
+                            }
+                            subscriptions.insert(topic.to_string(), sender);
+                            PendingAction::Subscribe(topic)
+                        }
+                        Action::Unsubscribe { topic } => PendingAction::Unsubscribe(topic),
+                        Action::Publish => PendingAction::Publish
+                    };
+                    pending.insert(request.request_id, PendingRequest { responder: request.responder, action });
+                    if sink.send(Message::Text(request.frame)).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    let _ = sink.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => handle_frame(&text, &mut subscriptions, &mut pending),
+                Some(Ok(Message::Binary(bytes))) => handle_binary(&bytes, &mut subscriptions),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => ()
+            },
+            _ = ping.tick() => {
+                // forget the requests whose caller stopped waiting
+                pending.retain(|_, pending| !pending.responder.is_closed());
+                if sink.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn dispatch(event: Event, subscriptions: &mut HashMap<String, mpsc::UnboundedSender<Event>>) {
+    subscriptions.retain(|pattern, sender| {
+        !topic::matches(pattern, &event.topic) || sender.send(event.clone()).is_ok()
+    });
+}
+
+// the client does not negotiate an encoding, so binary frames are always binary event frames
+fn handle_binary(bytes: &[u8], subscriptions: &mut HashMap<String, mpsc::UnboundedSender<Event>>) {
+    match frame::decode_event(bytes) {
+        Some((topic, seq, payload)) => dispatch(Event { topic, seq, payload: Payload::Binary(payload.to_vec()) }, subscriptions),
+        None => warn!("Invalid binary frame of {} bytes from flight", bytes.len())
+    }
+}
+
+fn handle_frame(text: &str, subscriptions: &mut HashMap<String, mpsc::UnboundedSender<Event>>, pending: &mut HashMap<String, PendingRequest>) {
+    match serde_json::from_str(text) {
+        Ok(Frame::Event(event)) => {
+            dispatch(Event { topic: event.topic, seq: event.seq, payload: Payload::Json(event.payload) }, subscriptions);
+        }
+        Ok(Frame::Response { request_id }) => {
+            if let Some(pending) = pending.remove(&request_id) {
+                if let PendingAction::Unsubscribe(topic) = &pending.action {
+                    subscriptions.remove(topic);
+                }
+                let _ = pending.responder.send(Ok(()));
+            }
+        }
+        Ok(Frame::Error { request_id, code, message }) => {
+            match request_id.and_then(|request_id| pending.remove(&request_id)) {
+                Some(pending) => {
+                    if let PendingAction::Subscribe(topic) = &pending.action {
+                        subscriptions.remove(topic);
+                    }
+                    let _ = pending.responder.send(Err(Error::Rejected { code, message }));
+                }
+                None => warn!("Flight reported {}: {}", code, message)
+            }
+        }
+        Err(error) => warn!("Unexpected frame from flight: {}", error)
+    }
+}
@@ -0,0 +1,37 @@
+use serde::Deserialize;
+use serde_json::Value;
+use crate::native::error::FlightClientError;
+
+pub type Error = FlightClientError;
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An event delivered on a subscription. `seq` is missing for events flight received without one.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub topic: String,
+    pub seq: Option<u64>,
+    pub payload: Payload
+}
+
+/// Payload of an event, which is raw bytes for events published with a binary payload.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Json(Value),
+    Binary(Vec<u8>)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct JsonEvent {
+    pub topic: String,
+    pub seq: Option<u64>,
+    pub payload: Value
+}
+
+// text frames sent by flight, while binary payloads arrive as binary event frames
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum Frame {
+    Event(JsonEvent),
+    Response { request_id: String },
+    Error { request_id: Option<String>, code: String, message: String }
+}
@@ -0,0 +1,25 @@
+const SEPARATOR: char = '.';
+const SINGLE_WILDCARD: &str = "*";
+const MULTI_WILDCARD: &str = ">";
+const PRESENCE_SUFFIX: &str = "/presence";
+
+/// Returns true when an event published on `topic` belongs to the subscription `pattern`,
+/// where `*` matches exactly one token and `>` one or more trailing tokens.
+/// Flight never delivers presence topics to patterns, so they only match themselves.
+pub fn matches(pattern: &str, topic: &str) -> bool {
+    if topic.ends_with(PRESENCE_SUFFIX) {
+        return pattern == topic;
+    }
+
+    let mut topic_tokens = topic.split(SEPARATOR);
+    for token in pattern.split(SEPARATOR) {
+        if token == MULTI_WILDCARD {
+            return topic_tokens.next().is_some();
+        }
+        match topic_tokens.next() {
+            Some(topic_token) if token == SINGLE_WILDCARD || token == topic_token => (),
+            _ => return false
+        }
+    }
+    topic_tokens.next().is_none()
+}
@@ -0,0 +1,106 @@
+//! Tracing shared by flight and traffic-control.
+use actix_web::http::HeaderMap;
+use opentelemetry::{
+    global, sdk, Context, KeyValue,
+    propagation::{Extractor, TextMapPropagator},
+    sdk::propagation::TraceContextPropagator,
+    trace::{SpanKind, TraceContextExt, Tracer}
+};
+use once_cell::sync::{Lazy, OnceCell};
+use std::{collections::HashMap, sync::Mutex};
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+// uninstalled on shutdown, which exports the remaining spans
+static PIPELINE: Lazy<Mutex<Option<opentelemetry_otlp::Uninstall>>> = Lazy::new(|| Mutex::new(None));
+
+static SERVICE_NAME: OnceCell<&'static str> = OnceCell::new();
+
+pub struct TracingConfig {
+    // host and port of the OTLP collector, receiving spans over gRPC
+    pub endpoint: Option<String>
+}
+
+pub fn get_tracing_config() -> TracingConfig {
+    TracingConfig {
+        endpoint: std::env::var("OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Installs the W3C trace context propagator, and exports the spans of the service over OTLP when a collector is configured.
+/// Spans are exported in batches by a task of the tokio runtime the server runs on.
+pub fn init(service_name: &'static str) {
+    let _ = SERVICE_NAME.set(service_name);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let config = get_tracing_config();
+    if let Some(endpoint) = config.endpoint {
+        let result = opentelemetry_otlp::new_pipeline()
+            .with_endpoint(format!("http://{}", endpoint))
+            .with_trace_config(sdk::trace::config().with_resource(sdk::Resource::new(vec![
+                KeyValue::new("service.name", service_name)
+            ])))
+            .install();
+        match result {
+            Ok((_, uninstall)) => {
+                log::info!("Exporting traces to {}", endpoint);
+                *PIPELINE.lock().unwrap() = Some(uninstall);
+            }
+            Err(error) => log::warn!("Unable to export traces to {}: {}", endpoint, error)
+        }
+    }
+}
+
+/// Flushes the spans that have not been exported yet.
+pub fn shutdown() {
+    PIPELINE.lock().unwrap().take();
+}
+
+/// Returns the trace context carried by the `traceparent` header of a request, if any.
+pub fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Returns the trace context of a `traceparent` value received along with an event, if any.
+pub fn parse(traceparent: Option<&str>) -> Context {
+    let mut carrier = HashMap::new();
+    if let Some(traceparent) = traceparent {
+        carrier.insert(TRACEPARENT_HEADER.to_string(), traceparent.to_string());
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+/// Returns the `traceparent` value to forward to the next hop, or `None` outside of a trace.
+pub fn traceparent(cx: &Context) -> Option<String> {
+    let mut carrier = HashMap::new();
+    TraceContextPropagator::new().inject_context(cx, &mut carrier);
+    carrier.remove(TRACEPARENT_HEADER)
+}
+
+/// Starts a span as a child of `parent`, which ends once the returned context and its clones are dropped.
+pub fn span(name: &'static str, kind: SpanKind, parent: &Context, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(SERVICE_NAME.get().copied().unwrap_or_default());
+    let span = tracer.span_builder(name)
+        .with_kind(kind)
+        .with_parent_context(parent.clone())
+        .with_attributes(attributes)
+        .start(&tracer);
+    parent.with_span(span)
+}
+
+/// Returns a context referring to the span of `cx` without keeping the span from ending.
+pub fn detach(cx: &Context) -> Context {
+    Context::new().with_remote_span_context(cx.span().span_context().clone())
+}
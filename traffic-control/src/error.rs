@@ -9,7 +9,10 @@ pub enum FlockError {
     InternalError,
 
     #[display(fmt = "Bad Request")]
-    BadRequest
+    BadRequest,
+
+    #[display(fmt = "Payload Too Large")]
+    PayloadTooLarge
 }
 
 impl From<reqwest::Error> for FlockError {
@@ -33,6 +36,14 @@ impl From<actix_redis::Error> for FlockError {
     }
 }
 
+#[cfg(feature = "watchtower")]
+impl From<watchtower_client::Error> for FlockError {
+    fn from(error: watchtower_client::Error) -> Self {
+        println!("WatchtowerClient Error: {:?}", error);
+        FlockError::InternalError
+    }
+}
+
 impl From<std::time::SystemTimeError> for FlockError {
     fn from(error: std::time::SystemTimeError) -> Self {
         println!("SystemTime Error: {:?}", error);
@@ -54,6 +65,13 @@ impl From<actix_web::Error> for FlockError {
     }    
 }
 
+impl From<actix_web::error::PayloadError> for FlockError {
+    fn from(error: actix_web::error::PayloadError) -> Self {
+        println!("Payload Error: {:?}", error);
+        FlockError::BadRequest
+    }
+}
+
 impl error::ResponseError for FlockError {
     fn error_response(&self) -> HttpResponse {
         HttpResponseBuilder::new(self.status_code())
@@ -64,7 +82,8 @@ impl error::ResponseError for FlockError {
     fn status_code(&self) -> StatusCode {
         match *self {
             FlockError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
-            FlockError::BadRequest => StatusCode::BAD_REQUEST
+            FlockError::BadRequest => StatusCode::BAD_REQUEST,
+            FlockError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE
         }
     }
 }
\ No newline at end of file
@@ -1,6 +1,5 @@
 use actix_redis::RedisActor;
 use actix_web::{middleware, web, App, HttpServer};
-use watchtower_client::WatchtowerClient;
 
 mod routes;
 mod error;
@@ -9,7 +8,8 @@ mod resources;
 mod utils;
 
 use crate::{
-    types::AppState,
+    types::{AppState, FlightLinks, TargetHealth, Patterns},
+    resources::{reaper, discovery},
     utils::env
 };
 
@@ -17,30 +17,56 @@ use crate::{
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "traffic_control=info,actix_web=info,actix_redis=info");
     env_logger::init();
+    telemetry::init("traffic-control");
 
     let redis_info = env::get_redis_info();
     let redis_addr = RedisActor::start(format!("{}:{}", redis_info.host, redis_info.port));
 
     let instance_info = env::get_instance_info();
 
-    let watchtower_config = env::get_watchtower_config();
-    let watchtower_client = WatchtowerClient::new(watchtower_config.urls, &watchtower_config.username, &watchtower_config.password);
-    watchtower_client.register("traffic-control", &instance_info.host, instance_info.port).await.unwrap();
+    // flight finds this instance once it is registered, which is retried until it succeeds
+    actix::spawn(discovery::run(discovery::from_env(redis_addr.clone()), instance_info.host.to_string(), instance_info.port));
 
+    let delivery_mode = env::get_delivery_mode();
+    log::info!("Delivering events with mode {:?}", delivery_mode);
+
+    let lease_config = env::get_lease_config();
+    let health = TargetHealth::new(redis_addr.clone(), &lease_config);
+
+    // shared by the workers, so that the patterns are cached once
+    let patterns = Patterns::new(redis_addr.clone());
+
+    let link_config = env::get_link_config();
+    let links = if link_config.enabled && delivery_mode == env::DeliveryMode::Http {
+        Some(FlightLinks::new(link_config.capacity, reqwest::Client::new(), env::get_auth_info(), health.clone()))
+    } else {
+        None
+    };
+    actix::spawn(reaper::run(redis_addr.clone(), links.clone(), lease_config.reap_interval_seconds));
 
     HttpServer::new(move || App::new()
         .wrap(middleware::Logger::default())
         .data(AppState {
             redis_addr: redis_addr.clone(),
             http_client: reqwest::Client::new(),
-            auth: env::get_auth_info()
+            auth: env::get_auth_info(),
+            history: env::get_history_config(),
+            delivery_mode,
+            lease: lease_config.clone(),
+            health: health.clone(),
+            patterns: patterns.clone(),
+            links: links.clone()
         })
         .service(
             web::scope("/api/v1")
             .configure(routes::api::v1::config)
         )
+        .service(web::resource("/metrics").route(web::get().to(routes::metrics::index)))
     )
     .bind(format!("0.0.0.0:{}", instance_info.port))?
     .run()
-    .await
+    .await?;
+
+    telemetry::shutdown();
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,105 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use redis_async::resp_array;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "watchtower")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "watchtower")]
+use watchtower_client::WatchtowerClient;
+use crate::{
+    types::Result,
+    utils::{env::{self, DiscoveryMode}, resp}
+};
+
+#[cfg(feature = "watchtower")]
+const SERVICE_ID: &str = "traffic-control";
+// sorted set of the registered instances, scored by the expiry of their registration
+const REDIS_INSTANCES_KEY: &str = "services:traffic-control";
+const REGISTRATION_INTERVAL_SECONDS: u64 = 10;
+const REGISTRATION_TTL_SECONDS: u64 = 30;
+
+/// Makes this instance known to flight, which looks it up with the same kind of discovery.
+pub trait Discovery {
+    /// Registers the instance, or renews its registration. Called every few seconds for as long as the instance runs.
+    fn register<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<()>>;
+}
+
+/// Registers the instance with watchtower once.
+#[cfg(feature = "watchtower")]
+pub struct WatchtowerDiscovery {
+    client: WatchtowerClient,
+    registered: AtomicBool
+}
+
+#[cfg(feature = "watchtower")]
+impl Discovery for WatchtowerDiscovery {
+    fn register<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            if !self.registered.load(Ordering::Acquire) {
+                self.client.register(SERVICE_ID, host, port).await?;
+                self.registered.store(true, Ordering::Release);
+                log::info!("Registered with watchtower");
+            }
+            Ok(())
+        }.boxed_local()
+    }
+}
+
+/// Leaves the instance to be listed in the configuration of flight or in DNS records.
+pub struct ExternalDiscovery;
+
+impl Discovery for ExternalDiscovery {
+    fn register<'a>(&'a self, _: &'a str, _: u16) -> LocalBoxFuture<'a, Result<()>> {
+        async {
+            Ok(())
+        }.boxed_local()
+    }
+}
+
+/// Registers the instance in Redis until shortly after it stops renewing its registration.
+pub struct RedisDiscovery {
+    redis: Addr<RedisActor>
+}
+
+impl Discovery for RedisDiscovery {
+    fn register<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let instance = format!("{}:{}", host, port);
+            let expiry = (now + REGISTRATION_TTL_SECONDS).to_string();
+            resp::send(&self.redis, Command(resp_array!["ZADD", REDIS_INSTANCES_KEY, expiry, instance])).await?;
+            resp::send(&self.redis, Command(resp_array!["ZREMRANGEBYSCORE", REDIS_INSTANCES_KEY, "-inf", format!("({}", now)])).await?;
+            Ok(())
+        }.boxed_local()
+    }
+}
+
+/// Builds the discovery selected by `DISCOVERY`.
+pub fn from_env(redis: Addr<RedisActor>) -> Box<dyn Discovery> {
+    let mode = env::get_discovery_mode();
+    log::info!("Registering with {:?} discovery", mode);
+    match mode {
+        #[cfg(feature = "watchtower")]
+        DiscoveryMode::Watchtower => {
+            let watchtower_config = env::get_watchtower_config();
+            Box::new(WatchtowerDiscovery {
+                client: WatchtowerClient::new(watchtower_config.urls, &watchtower_config.username, &watchtower_config.password),
+                registered: AtomicBool::new(false)
+            })
+        }
+        DiscoveryMode::Static | DiscoveryMode::Dns => Box::new(ExternalDiscovery),
+        DiscoveryMode::Redis => Box::new(RedisDiscovery { redis })
+    }
+}
+
+/// Keeps the instance registered, retrying whenever the registration fails.
+pub async fn run(discovery: Box<dyn Discovery>, host: String, port: u16) {
+    let mut interval = tokio::time::interval(Duration::from_secs(REGISTRATION_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(error) = discovery.register(&host, port).await {
+            log::warn!("Unable to register traffic-control: {}", error);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use actix_codec::Framed;
+use awc::{ws, BoxedSocket};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::Duration
+};
+use tokio::{sync::mpsc, time};
+use crate::{
+    types::{Result, Error, AuthInfo, BatchEvent, Delivery, TargetHealth, SEQUENCE_HEADER, JSON_CONTENT_TYPE, BINARY_CONTENT_TYPE},
+    utils::{frame, metrics::{metrics, HTTP_TRANSPORT, STREAM_TRANSPORT}}
+};
+use telemetry::TRACEPARENT_HEADER;
+
+const MIN_RECONNECT_DELAY_MS: u64 = 100;
+const MAX_RECONNECT_DELAY_MS: u64 = 10_000;
+const PING_INTERVAL_SEC: u64 = 15;
+
+#[derive(Clone)]
+pub struct LinkEvent {
+    pub topic: String,
+    pub seq: u64,
+    pub payload: Bytes,
+    pub binary: bool,
+    // trace context forwarded to flight
+    pub traceparent: Option<String>
+}
+
+/// Sending end of a long-lived WebSocket connection to the `/api/v1/stream` route of a flight instance.
+/// Events are queued in a bounded channel and written by a task that reconnects with exponential backoff.
+#[derive(Clone)]
+struct FlightLink {
+    sender: mpsc::Sender<LinkEvent>,
+    connected: Arc<AtomicBool>
+}
+
+/// Links to every flight instance this traffic-control has delivered events to, shared by all workers.
+#[derive(Clone)]
+pub struct FlightLinks {
+    links: Arc<Mutex<HashMap<String, FlightLink>>>,
+    capacity: usize,
+    http_client: reqwest::Client,
+    auth: AuthInfo,
+    // counts the deliveries of the links like those of plain requests
+    health: TargetHealth
+}
+
+impl FlightLinks {
+    pub fn new(capacity: usize, http_client: reqwest::Client, auth: AuthInfo, health: TargetHealth) -> FlightLinks {
+        FlightLinks {
+            links: Arc::new(Mutex::new(HashMap::new())),
+            capacity,
+            http_client,
+            auth,
+            health
+        }
+    }
+
+    /// Queues the event on the link to the target, opening the link on first use.
+    /// Returns the event back when the link is down or full so that the caller can fall back to HTTP.
+    pub fn try_send(&self, target: &str, event: LinkEvent) -> std::result::Result<(), LinkEvent> {
+        let link = {
+            let mut links = self.links.lock().unwrap();
+            match links.get(target) {
+                Some(link) => link.clone(),
+                None => {
+                    let link = self.open(target);
+                    links.insert(target.to_string(), link.clone());
+                    link
+                }
+            }
+        };
+
+        if !link.connected.load(Ordering::Acquire) {
+            metrics().send_failures.with_label_values(&[target, STREAM_TRANSPORT]).inc();
+            return Err(event);
+        }
+
+        let mut sender = link.sender;
+        sender.try_send(event).map_err(|error| {
+            metrics().send_failures.with_label_values(&[target, STREAM_TRANSPORT]).inc();
+            match error {
+                mpsc::error::TrySendError::Full(event) => event,
+                mpsc::error::TrySendError::Closed(event) => event
+            }
+        })
+    }
+
+    /// Drops the link to the target, which stops its task once the queued events are written.
+    pub fn close(&self, target: &str) {
+        self.links.lock().unwrap().remove(target);
+    }
+
+    fn open(&self, target: &str) -> FlightLink {
+        let (sender, receiver) = mpsc::channel(self.capacity);
+        let connected = Arc::new(AtomicBool::new(false));
+        actix::spawn(run(target.to_string(), receiver, connected.clone(), self.clone()));
+        FlightLink {
+            sender,
+            connected
+        }
+    }
+}
+
+async fn connect(target: &str, auth: &AuthInfo) -> std::result::Result<Framed<BoxedSocket, ws::Codec>, awc::error::WsClientError> {
+    let url = format!("http://{}/api/v1/stream", target);
+    let (_, framed) = awc::Client::new()
+        .ws(&url)
+        .basic_auth(&auth.username, Some(auth.password.as_str()))
+        .connect().await?;
+    Ok(framed)
+}
+
+async fn run(target: String, mut receiver: mpsc::Receiver<LinkEvent>, connected: Arc<AtomicBool>, links: FlightLinks) {
+    let mut reconnect_delay = Duration::from_millis(MIN_RECONNECT_DELAY_MS);
+    loop {
+        match connect(&target, &links.auth).await {
+            Ok(framed) => {
+                log::info!("Opened stream to {}", target);
+                connected.store(true, Ordering::Release);
+                record_health(&target, true, &links).await;
+                reconnect_delay = Duration::from_millis(MIN_RECONNECT_DELAY_MS);
+
+                let (mut sink, mut stream) = framed.split();
+                let mut ping = time::interval(Duration::from_secs(PING_INTERVAL_SEC));
+                loop {
+                    tokio::select! {
+                        event = receiver.recv() => match event {
+                            Some(event) => {
+                                let frame = match frame::encode_event(&event.topic, event.seq, &event.payload, event.binary, event.traceparent.as_deref()) {
+                                    Some(frame) => frame,
+                                    None => {
+                                        post_link_event(&target, event, &links).await;
+                                        continue;
+                                    }
+                                };
+                                let timer = metrics().send_latency.with_label_values(&[&target, STREAM_TRANSPORT]).start_timer();
+                                let sent = sink.send(ws::Message::Binary(frame)).await;
+                                timer.observe_duration();
+                                if let Err(error) = sent {
+                                    metrics().send_failures.with_label_values(&[&target, STREAM_TRANSPORT]).inc();
+                                    log::warn!("Failed to write to stream of {}: {}", target, error);
+                                    post_link_event(&target, event, &links).await;
+                                    break;
+                                }
+                            }
+                            None => return
+                        },
+                        message = stream.next() => match message {
+                            Some(Ok(ws::Frame::Ping(message))) if sink.send(ws::Message::Pong(message.clone())).await.is_err() => break,
+                            Some(Ok(ws::Frame::Close(_))) | Some(Err(_)) | None => break,
+                            _ => ()
+                        },
+                        _ = ping.tick() => {
+                            if sink.send(ws::Message::Ping(Bytes::new())).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                connected.store(false, Ordering::Release);
+                log::warn!("Lost stream to {}", target);
+            }
+            Err(error) => {
+                log::warn!("Unable to open stream to {}: {}", target, error);
+                record_health(&target, false, &links).await;
+            }
+        }
+
+        // events queued before the link went down are delivered over HTTP until the next attempt
+        let deadline = time::Instant::now() + reconnect_delay;
+        loop {
+            match time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(event)) => post_link_event(&target, event, &links).await,
+                Ok(None) => return,
+                Err(_) => break
+            }
+        }
+        reconnect_delay = std::cmp::min(reconnect_delay * 2, Duration::from_millis(MAX_RECONNECT_DELAY_MS));
+    }
+}
+
+async fn post_link_event(target: &str, event: LinkEvent, links: &FlightLinks) {
+    let result = post_event(target, event, &links.http_client, &links.auth).await;
+    if let Err(error) = &result {
+        log::error!("Unable to send request to {}: {}", target, error);
+    }
+    record_health(target, result.is_ok(), links).await;
+}
+
+async fn record_health(target: &str, delivered: bool, links: &FlightLinks) {
+    if let Err(error) = links.health.record(target, delivered, Some(links)).await {
+        log::warn!("Unable to record the health of {}: {}", target, error);
+    }
+}
+
+/// Sends the event with a plain HTTP request, returning the number of sockets flight delivered it to.
+pub async fn post_event(target: &str, event: LinkEvent, http_client: &reqwest::Client, auth: &AuthInfo) -> Result<usize> {
+    let url = format!("http://{}/api/v1/event/{}", target, event.topic);
+    let content_type = if event.binary { BINARY_CONTENT_TYPE } else { JSON_CONTENT_TYPE };
+    let timer = metrics().send_latency.with_label_values(&[target, HTTP_TRANSPORT]).start_timer();
+    let mut req = http_client
+        .post(&url)
+        .basic_auth(&auth.username, Some(&auth.password))
+        .header(SEQUENCE_HEADER, event.seq.to_string());
+    if let Some(traceparent) = event.traceparent {
+        req = req.header(TRACEPARENT_HEADER, traceparent);
+    }
+    let res = req
+        .body(event.payload).header("content-type", content_type)
+        .send().await;
+    timer.observe_duration();
+    read_delivery(target, res).await
+}
+
+/// Sends a batch of JSON events with a single request, returning the number of sockets flight delivered them to.
+pub async fn post_events(target: &str, events: &[BatchEvent], traceparent: Option<String>, http_client: &reqwest::Client, auth: &AuthInfo) -> Result<usize> {
+    let url = format!("http://{}/api/v1/events", target);
+    let body = serde_json::to_vec(events)?;
+    let timer = metrics().send_latency.with_label_values(&[target, HTTP_TRANSPORT]).start_timer();
+    let mut req = http_client
+        .post(&url)
+        .basic_auth(&auth.username, Some(&auth.password));
+    if let Some(traceparent) = traceparent {
+        req = req.header(TRACEPARENT_HEADER, traceparent);
+    }
+    let res = req
+        .body(body).header("content-type", JSON_CONTENT_TYPE)
+        .send().await;
+    timer.observe_duration();
+    read_delivery(target, res).await
+}
+
+// counts failed requests and reads the number of sockets from the reply of flight
+async fn read_delivery(target: &str, res: std::result::Result<reqwest::Response, reqwest::Error>) -> Result<usize> {
+    let res = res.inspect_err(|_| {
+        metrics().send_failures.with_label_values(&[target, HTTP_TRANSPORT]).inc();
+    })?;
+    if res.status() != reqwest::StatusCode::OK {
+        metrics().send_failures.with_label_values(&[target, HTTP_TRANSPORT]).inc();
+        log::error!("Unable to send request to {}: {}", target, res.status());
+        return Err(Error::InternalError);
+    }
+
+    // older flight instances reply with an empty body
+    let body = res.text().await?;
+    Ok(serde_json::from_str::<Delivery>(&body).map_or(0, |delivery| delivery.delivered))
+}
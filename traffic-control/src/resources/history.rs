@@ -0,0 +1,96 @@
+use serde::Serialize;
+use redis_async::resp::RespValue;
+use crate::{
+    types::{Result, Error},
+    utils::resp
+};
+
+/// An event kept in the `history:{topic}` stream, parsed from a single `XRANGE` entry.
+/// Binary payloads are serialized as base64 strings and flagged with `binary`.
+#[derive(Serialize, Debug)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub payload: serde_json::Value,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub binary: bool
+}
+
+impl std::convert::TryFrom<RespValue> for HistoryEntry {
+    type Error = Error;
+
+    fn try_from(value: RespValue) -> Result<Self> {
+        // each entry is an array of the stream id followed by the flattened field-value pairs
+        let fields = match value {
+            RespValue::Array(mut entry) if entry.len() == 2 => entry.pop(),
+            _ => None
+        };
+
+        let mut seq = None;
+        let mut payload = None;
+        let mut binary = false;
+        if let Some(RespValue::Array(fields)) = fields {
+            let mut iter = fields.into_iter();
+            while let (Some(field), Some(value)) = (iter.next(), iter.next()) {
+                match resp::to_string(field)?.as_str() {
+                    "seq" => seq = Some(resp::to_string(value)?.parse::<u64>().map_err(|_| Error::InternalError)?),
+                    "binary" => binary = resp::to_string(value)? == "1",
+                    "payload" => payload = Some(value),
+                    _ => ()
+                }
+            }
+        }
+
+        // the payload is only parsed once all fields are known, as it depends on the binary flag
+        let payload = match payload {
+            Some(RespValue::BulkString(bytes)) if binary => serde_json::Value::String(base64::encode(bytes)),
+            Some(value) => serde_json::from_str(&resp::to_string(value)?)?,
+            None => return Err(Error::InternalError)
+        };
+
+        match seq {
+            Some(seq) => Ok(HistoryEntry { seq, payload, binary }),
+            None => Err(Error::InternalError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis_async::resp_array;
+    use std::convert::TryFrom;
+
+    fn entry(fields: RespValue) -> RespValue {
+        resp_array!["3-1700000000000", fields]
+    }
+
+    #[test]
+    fn parses_json_entries() {
+        let value = entry(resp_array!["seq", "3", "binary", "0", "payload", "{\"message\":\"hello\"}"]);
+        let entry = HistoryEntry::try_from(value).unwrap();
+        assert_eq!(entry.seq, 3);
+        assert_eq!(entry.payload, serde_json::json!({ "message": "hello" }));
+        assert!(!entry.binary);
+    }
+
+    #[test]
+    fn encodes_binary_payloads_as_base64() {
+        let value = entry(RespValue::Array(vec![
+            RespValue::from("payload"), RespValue::BulkString(vec![0, 1, 255]),
+            RespValue::from("binary"), RespValue::from("1"),
+            RespValue::from("seq"), RespValue::from("3")
+        ]));
+        let entry = HistoryEntry::try_from(value).unwrap();
+        assert_eq!(entry.payload, serde_json::Value::String("AAH/".to_string()));
+        assert!(entry.binary);
+    }
+
+    #[test]
+    fn rejects_incomplete_entries() {
+        assert!(HistoryEntry::try_from(entry(resp_array!["payload", "{}"])).is_err());
+        assert!(HistoryEntry::try_from(entry(resp_array!["seq", "3"])).is_err());
+        assert!(HistoryEntry::try_from(entry(resp_array!["seq", "three", "payload", "{}"])).is_err());
+        assert!(HistoryEntry::try_from(entry(resp_array!["seq", "3", "payload", "{"])).is_err());
+        assert!(HistoryEntry::try_from(RespValue::Nil).is_err());
+    }
+}
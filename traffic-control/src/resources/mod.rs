@@ -1 +1,7 @@
-pub mod target_info;
\ No newline at end of file
+pub mod target_info;
+pub mod history;
+pub mod flight_link;
+pub mod target_health;
+pub mod reaper;
+pub mod discovery;
+pub mod patterns;
@@ -0,0 +1,256 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use futures_util::future::{join, join_all};
+use redis_async::{resp::RespValue, resp_array};
+use std::{collections::HashMap, sync::{Arc, RwLock}};
+use crate::{
+    types::{Result, Error},
+    utils::{topic::{SEPARATOR, SINGLE_WILDCARD, MULTI_WILDCARD}, resp}
+};
+
+pub const PATTERNS_KEY: &str = "patterns";
+// incremented whenever a pattern is added or forgotten, so that instances know when their copy of the patterns is stale
+pub const PATTERNS_VERSION_KEY: &str = "patterns:version";
+
+// removes a target from a pattern topic and forgets the pattern once nobody subscribes to it anymore
+const REMOVE_PATTERN_TARGET_SCRIPT: &str = "redis.call('SREM', KEYS[1], ARGV[1]) \
+    if redis.call('SCARD', KEYS[1]) == 0 and redis.call('SREM', KEYS[2], ARGV[2]) == 1 then redis.call('INCR', KEYS[3]) end \
+    return 1";
+
+// collects the targets subscribed to any of the topics, flagging the ones whose deliveries failed since the last successful one
+const TARGETS_SCRIPT: &str = "local targets = redis.call('SUNION', unpack(KEYS)) \
+    local failing = {} \
+    for i, target in ipairs(targets) do failing[i] = redis.call('EXISTS', 'failures:' .. target) end \
+    return {targets, failing}";
+
+pub fn remove_target_command(pattern: &str, str_info: String) -> Command {
+    Command(resp_array![
+        "EVAL", REMOVE_PATTERN_TARGET_SCRIPT, "3", format!("topic:{}", pattern), PATTERNS_KEY, PATTERNS_VERSION_KEY,
+        str_info, pattern
+    ])
+}
+
+/// Patterns stored by token, so that the ones matching a topic are found without going through all of them.
+#[derive(Default, Debug)]
+struct PatternIndex {
+    children: HashMap<String, PatternIndex>,
+    pattern: Option<String>
+}
+
+impl PatternIndex {
+    fn insert(&mut self, pattern: String) {
+        let node = pattern.split(SEPARATOR)
+            .fold(self, |node, token| node.children.entry(token.to_string()).or_default());
+        node.pattern = Some(pattern);
+    }
+
+    fn collect<'a>(&'a self, tokens: &[&str], patterns: &mut Vec<&'a str>) {
+        match tokens.split_first() {
+            Some((token, rest)) => {
+                if let Some(child) = self.children.get(*token) {
+                    child.collect(rest, patterns);
+                }
+                if let Some(child) = self.children.get(SINGLE_WILDCARD) {
+                    child.collect(rest, patterns);
+                }
+                if let Some(pattern) = self.children.get(MULTI_WILDCARD).and_then(|child| child.pattern.as_deref()) {
+                    patterns.push(pattern);
+                }
+            }
+            None => patterns.extend(self.pattern.as_deref())
+        }
+    }
+
+    /// Returns the patterns matching a topic, which is not a pattern itself.
+    fn matching(&self, topic: &str) -> Vec<&str> {
+        let tokens: Vec<&str> = topic.split(SEPARATOR).collect();
+        let mut patterns = Vec::new();
+        self.collect(&tokens, &mut patterns);
+        patterns
+    }
+}
+
+/// A flight instance subscribed to a topic.
+pub struct Target {
+    pub info: RespValue,
+    // whether a delivery failure of the instance is counted
+    pub failing: bool
+}
+
+impl Target {
+    fn parse(value: RespValue) -> Result<Vec<Target>> {
+        let (targets, failing) = match value {
+            RespValue::Array(mut value) if value.len() == 2 => (value.remove(0), value.remove(0)),
+            _ => return Err(Error::InternalError)
+        };
+        match (targets, failing) {
+            (RespValue::Array(targets), RespValue::Array(failing)) => Ok(targets.into_iter().zip(failing)
+                .map(|(info, failing)| Target { info, failing: failing == RespValue::Integer(1) })
+                .collect()),
+            _ => Err(Error::InternalError)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    // version of the patterns the index was built from, none until the patterns are read once
+    version: Option<i64>,
+    index: PatternIndex
+}
+
+/// Caches the patterns subscribed to, which are read again from Redis only once their version changed.
+#[derive(Clone)]
+pub struct Patterns {
+    redis: Addr<RedisActor>,
+    cache: Arc<RwLock<Cache>>
+}
+
+impl Patterns {
+    pub fn new(redis: Addr<RedisActor>) -> Patterns {
+        Patterns {
+            redis,
+            cache: Arc::new(RwLock::new(Cache::default()))
+        }
+    }
+
+    /// Looks up the targets subscribed to every topic or to any pattern matching it. The version of the patterns
+    /// is read along with the targets, which are only looked up again when the cached patterns turn out to be stale.
+    pub async fn targets(&self, topics: &[&str]) -> Result<Vec<Result<Vec<Target>>>> {
+        let (version, commands) = self.targets_commands(topics);
+        let (current, targets) = join(self.version(), self.send_all(commands)).await;
+        let current = current?;
+        if version == Some(current) {
+            return Ok(targets);
+        }
+
+        self.refresh(current).await?;
+        let (_, commands) = self.targets_commands(topics);
+        Ok(self.send_all(commands).await)
+    }
+
+    // collects the targets subscribed to the topic itself or to any of the patterns matching it
+    fn targets_commands(&self, topics: &[&str]) -> (Option<i64>, Vec<Command>) {
+        let cache = self.cache.read().unwrap();
+        let commands = topics.iter()
+            .map(|topic| {
+                let keys: Vec<RespValue> = std::iter::once(*topic)
+                    .chain(cache.index.matching(topic))
+                    .map(|topic| RespValue::from(format!("topic:{}", topic)))
+                    .collect();
+                let header = vec![RespValue::from("EVAL"), RespValue::from(TARGETS_SCRIPT), RespValue::from(keys.len().to_string())];
+                Command(RespValue::Array(header.into_iter().chain(keys).collect()))
+            })
+            .collect();
+        (cache.version, commands)
+    }
+
+    async fn send_all(&self, commands: Vec<Command>) -> Vec<Result<Vec<Target>>> {
+        let sends = commands.into_iter().map(|command| async move { Target::parse(resp::send(&self.redis, command).await?) });
+        join_all(sends).await
+    }
+
+    async fn version(&self) -> Result<i64> {
+        match resp::send(&self.redis, Command(resp_array!["GET", PATTERNS_VERSION_KEY])).await? {
+            RespValue::Nil => Ok(0),
+            value => resp::to_string(value)?.parse().map_err(|_| Error::InternalError)
+        }
+    }
+
+    // the patterns are read after their version, so they include at least every change up to that version
+    async fn refresh(&self, version: i64) -> Result<()> {
+        let mut index = PatternIndex::default();
+        if let RespValue::Array(patterns) = resp::send(&self.redis, Command(resp_array!["SMEMBERS", PATTERNS_KEY])).await? {
+            for pattern in patterns {
+                index.insert(resp::to_string(pattern)?);
+            }
+        }
+
+        let mut cache = self.cache.write().unwrap();
+        // a concurrent lookup may have read newer patterns already
+        if cache.version.is_none_or(|cached| cached < version) {
+            *cache = Cache { version: Some(version), index };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // matches a single pattern, which the index must agree with:
+    // `*` matches exactly one token and `>` matches one or more trailing tokens
+    fn matches(pattern: &str, topic: &str) -> bool {
+        let mut topic_tokens = topic.split(SEPARATOR);
+        for token in pattern.split(SEPARATOR) {
+            if token == MULTI_WILDCARD {
+                return topic_tokens.next().is_some();
+            }
+            match topic_tokens.next() {
+                Some(topic_token) if token == SINGLE_WILDCARD || token == topic_token => (),
+                _ => return false
+            }
+        }
+        topic_tokens.next().is_none()
+    }
+
+    fn index(patterns: &[&str]) -> PatternIndex {
+        let mut index = PatternIndex::default();
+        for pattern in patterns {
+            index.insert(pattern.to_string());
+        }
+        index
+    }
+
+    fn sorted(mut patterns: Vec<&str>) -> Vec<&str> {
+        patterns.sort_unstable();
+        patterns
+    }
+
+    #[test]
+    fn finds_the_patterns_matching_a_topic() {
+        let index = index(&["news.*", "news.>", "*.sport", ">", "news.*.today", "weather.*"]);
+        assert_eq!(sorted(index.matching("news.sport")), vec!["*.sport", ">", "news.*", "news.>"]);
+        assert_eq!(sorted(index.matching("news.sport.today")), vec![">", "news.*.today", "news.>"]);
+        assert_eq!(sorted(index.matching("news")), vec![">"]);
+        assert_eq!(sorted(index.matching("weather.rain.today")), vec![">"]);
+    }
+
+    #[test]
+    fn wildcards_match_whole_tokens() {
+        let index = index(&["news.*", "news.>", "news.s*"]);
+        assert_eq!(sorted(index.matching("news.sport")), vec!["news.*", "news.>"]);
+        assert_eq!(index.matching("news.sport.today"), vec!["news.>"]);
+        assert!(index.matching("news").is_empty());
+    }
+
+    #[test]
+    fn parses_targets_with_their_failure_flags() {
+        let value = resp_array![resp_array!["a:1", "b:2"], RespValue::Array(vec![RespValue::Integer(0), RespValue::Integer(1)])];
+        let targets = Target::parse(value).unwrap();
+        assert_eq!(targets.iter().map(|target| (target.info.clone(), target.failing)).collect::<Vec<_>>(), vec![
+            (RespValue::from("a:1"), false),
+            (RespValue::from("b:2"), true)
+        ]);
+
+        let empty = resp_array![RespValue::Array(Vec::new()), RespValue::Array(Vec::new())];
+        assert!(Target::parse(empty).unwrap().is_empty());
+        assert!(Target::parse(RespValue::Nil).is_err());
+    }
+
+    #[test]
+    fn finds_nothing_without_patterns() {
+        assert!(PatternIndex::default().matching("news.sport").is_empty());
+    }
+
+    #[test]
+    fn agrees_with_matches() {
+        let patterns = ["a.*", "a.>", "*.b", "*.*", "a.*.c", "a.b.>", ">", "*"];
+        let index = index(&patterns);
+        for topic in ["a", "b", "a.b", "b.b", "a.b.c", "a.x.c", "a.b.c.d", "x.y.z"] {
+            let expected: Vec<&str> = patterns.iter().copied().filter(|pattern| matches(pattern, topic)).collect();
+            assert_eq!(sorted(index.matching(topic)), sorted(expected), "topic {}", topic);
+        }
+    }
+}
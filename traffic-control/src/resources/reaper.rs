@@ -0,0 +1,52 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use redis_async::{resp::RespValue, resp_array};
+use std::time::Duration;
+use crate::{
+    routes::api::v1::{purge_target, INSTANCES_KEY},
+    types::{Result, FlightLinks},
+    utils::resp
+};
+
+const REAPER_LOCK_KEY: &str = "reaper";
+
+/// Periodically purges the flight instances whose lease has expired, i.e. which stopped sending heartbeats.
+/// Every traffic-control instance runs a reaper, but only the one taking the lock reaps in each interval.
+pub async fn run(redis: Addr<RedisActor>, links: Option<FlightLinks>, interval_seconds: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds));
+    loop {
+        interval.tick().await;
+        let result = match lock(&redis, interval_seconds).await {
+            Ok(true) => reap(&redis, &links).await,
+            other => other.map(|_| ())
+        };
+        if let Err(error) = result {
+            log::warn!("Unable to reap expired flight instances: {}", error);
+        }
+    }
+}
+
+// the lock is left to expire, so that it is held for the whole interval
+async fn lock(redis: &Addr<RedisActor>, interval_seconds: u64) -> Result<bool> {
+    let command = Command(resp_array!["SET", REAPER_LOCK_KEY, "1", "NX", "EX", interval_seconds.max(1).to_string()]);
+    match resp::send(redis, command).await? {
+        RespValue::Nil => Ok(false),
+        _ => Ok(true)
+    }
+}
+
+async fn reap(redis: &Addr<RedisActor>, links: &Option<FlightLinks>) -> Result<()> {
+    if let RespValue::Array(instances) = resp::send(redis, Command(resp_array!["SMEMBERS", INSTANCES_KEY])).await? {
+        for instance in instances {
+            let target = resp::to_string(instance)?;
+            if let RespValue::Integer(0) = resp::send(redis, Command(resp_array!["EXISTS", format!("lease:{}", target)])).await? {
+                log::warn!("Lease of {} expired, removing its subscriptions", target);
+                purge_target(&target, redis).await?;
+                if let Some(links) = links {
+                    links.close(&target);
+                }
+            }
+        }
+    }
+    Ok(())
+}
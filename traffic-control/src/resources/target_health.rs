@@ -0,0 +1,82 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use redis_async::{resp::RespValue, resp_array};
+use std::{collections::HashSet, sync::{Arc, Mutex}};
+use crate::{
+    routes::api::v1::purge_target,
+    types::{Result, LeaseConfig, FlightLinks},
+    utils::resp
+};
+
+// counts a failure and resets the count once it reaches the limit, returning 1 then
+const RECORD_FAILURE_SCRIPT: &str = "local count = redis.call('INCR', KEYS[1]) \
+    redis.call('EXPIRE', KEYS[1], ARGV[2]) \
+    if count >= tonumber(ARGV[1]) then redis.call('DEL', KEYS[1]) return 1 end \
+    return 0";
+
+/// Counts the consecutive delivery failures of every flight instance in Redis, so that the failures
+/// seen by every traffic-control instance add up. Counts expire with the lease of the flight instance.
+#[derive(Clone)]
+pub struct TargetHealth {
+    redis: Addr<RedisActor>,
+    max_failures: u32,
+    ttl_seconds: u64,
+    // targets whose count is known to be set, which are the only ones a successful delivery has to reset
+    failing: Arc<Mutex<HashSet<String>>>
+}
+
+impl TargetHealth {
+    pub fn new(redis: Addr<RedisActor>, lease: &LeaseConfig) -> TargetHealth {
+        TargetHealth {
+            redis,
+            max_failures: lease.max_failures,
+            ttl_seconds: lease.ttl_seconds,
+            failing: Arc::new(Mutex::new(HashSet::new()))
+        }
+    }
+
+    /// Notes that the target has a count, as found along with the target, so that its next delivery resets it.
+    pub fn suspect(&self, target: &str) {
+        self.failing.lock().unwrap().insert(target.to_string());
+    }
+
+    /// Resets the count of the target, which this or other instances may have seen failing. Deliveries
+    /// to targets without a count do not reach Redis.
+    pub async fn record_success(&self, target: &str) {
+        if !self.failing.lock().unwrap().remove(target) {
+            return;
+        }
+        if let Err(error) = resp::send(&self.redis, Command(resp_array!["DEL", format!("failures:{}", target)])).await {
+            log::warn!("Unable to reset the failures of {}: {}", target, error);
+            self.suspect(target);
+        }
+    }
+
+    /// Returns true once the target has failed `max_failures` times in a row, resetting its count.
+    pub async fn record_failure(&self, target: &str) -> Result<bool> {
+        self.suspect(target);
+        let command = Command(resp_array![
+            "EVAL", RECORD_FAILURE_SCRIPT, "1", format!("failures:{}", target),
+            self.max_failures.to_string(), self.ttl_seconds.to_string()
+        ]);
+        let reached = resp::send(&self.redis, command).await? == RespValue::Integer(1);
+        if reached {
+            self.failing.lock().unwrap().remove(target);
+        }
+        Ok(reached)
+    }
+
+    /// Counts the outcome of a delivery, and purges the target once its deliveries failed `max_failures` times in a row.
+    pub async fn record(&self, target: &str, delivered: bool, links: Option<&FlightLinks>) -> Result<()> {
+        if delivered {
+            self.record_success(target).await;
+        } else if self.record_failure(target).await? {
+            log::warn!("Stopped sending events to {} after {} failures", target, self.max_failures);
+            purge_target(target, &self.redis).await?;
+            if let Some(links) = links {
+                links.close(target);
+            }
+        }
+        Ok(())
+    }
+}
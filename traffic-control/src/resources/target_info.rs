@@ -43,4 +43,26 @@ impl std::convert::TryFrom<RespValue> for TargetInfo {
             _ => Err(Error::InternalError)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn parses_targets_stored_in_redis() {
+        let target = TargetInfo::try_from(RespValue::BulkString(b"flight-1:8080".to_vec())).unwrap();
+        assert_eq!(target.to_string(), "flight-1:8080");
+        let target = TargetInfo::try_from(RespValue::SimpleString("10.0.0.1:80".to_string())).unwrap();
+        assert_eq!(target.to_string(), "10.0.0.1:80");
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        for target in ["flight-1", "flight-1:", "flight-1:port", "flight-1:70000"] {
+            assert!(TargetInfo::try_from(RespValue::BulkString(target.as_bytes().to_vec())).is_err(), "{}", target);
+        }
+        assert!(TargetInfo::try_from(RespValue::Integer(1)).is_err());
+        assert!(TargetInfo::try_from(RespValue::BulkString(vec![0xff, b':', b'1'])).is_err());
+    }
+}
@@ -1,38 +1,120 @@
 use actix_redis::Command;
-use actix_web::{web, guard, HttpResponse};
-use futures_util::future::join_all;
+use actix_web::{web, HttpRequest, HttpResponse, web::{Bytes, BytesMut}};
+use futures_util::{StreamExt, future::{join, join_all}};
+use opentelemetry::{Context, KeyValue, trace::SpanKind};
 use redis_async::{resp::RespValue, resp_array};
-use std::convert::TryFrom;
-use crate::types::{Result, Error, TargetInfo, AuthorizedReq, AppState, AuthInfo};
+use std::{collections::{BTreeSet, HashMap}, convert::TryFrom};
+use serde::Deserialize;
+use crate::{
+    types::{Result, Error, TargetInfo, HistoryEntry, Presence, PublishReport, BatchEvent, AuthorizedReq, AppState, HistoryConfig, DeliveryMode, EVENT_CHANNEL_PREFIX, JSON_CONTENT_TYPE},
+    resources::{flight_link::{LinkEvent, post_event, post_events}, patterns::{self, Target}},
+    utils::{topic, resp, frame, metrics::metrics}
+};
 
+pub const INSTANCES_KEY: &str = "instances";
 
-async fn subscribe(_: AuthorizedReq, path: web::Path<(String,)>, info: web::Query<TargetInfo>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
-    let (topic,) = path.into_inner();
-    let str_info = info.into_inner().to_string();
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+// bounds the events a publisher may send with a single request, which are all parsed before any is published
+const BATCH_PAYLOAD_LIMIT: usize = 16 * 1024 * 1024;
 
-    let redis = &app_state.redis_addr;
+// adds the target to the topic and the topic to the subscriptions of the target, registering the topic when it is a pattern,
+// in a single script so that purging the target cannot leave the target in the topic behind
+const SUBSCRIBE_SCRIPT: &str = "redis.call('SADD', KEYS[1], ARGV[1]) \
+    redis.call('SADD', KEYS[2], ARGV[2]) \
+    if ARGV[3] == '1' and redis.call('SADD', KEYS[3], ARGV[2]) == 1 then redis.call('INCR', KEYS[4]) end \
+    return 1";
 
-    let one = redis.send(Command(resp_array!["SADD", format!("topic:{}", topic), str_info.to_string()]));
-    let two = redis.send(Command(resp_array!["SADD", format!("subscription:{}", str_info), topic]));
+// removes the target from every topic it subscribed to, forgetting the patterns nobody subscribes to anymore, and forgets the target
+const PURGE_TARGET_SCRIPT: &str = "local forgotten = false \
+    for _, topic in ipairs(redis.call('SMEMBERS', KEYS[1])) do \
+        local key = 'topic:' .. topic \
+        redis.call('SREM', key, ARGV[1]) \
+        if redis.call('SCARD', key) == 0 and redis.call('SREM', KEYS[4], topic) == 1 then forgotten = true end \
+    end \
+    if forgotten then redis.call('INCR', KEYS[5]) end \
+    redis.call('DEL', KEYS[1], KEYS[3]) \
+    redis.call('SREM', KEYS[2], ARGV[1]) \
+    return 1";
 
-    let res: Vec<Result<RespValue>> =
-        join_all(vec![one, two].into_iter())
-            .await
-            .into_iter()
-            .map(|item| {
-                item.map_err(Error::from)
-                    .and_then(|res| res.map_err(Error::from))
-            })
-            .collect();
+// stamps the event with the next sequence number of its topic and appends it to the topic history under the id
+// `{seq}-{ms}`, so that the history can be read from a sequence number, trimming the history to the configured length and age.
+// Both keys expire once the topic received no event for the age of the history, which restarts the sequence of the topic
+const APPEND_HISTORY_SCRIPT: &str = "local seq = string.format('%d', redis.call('INCR', KEYS[1])) \
+    local now = redis.call('TIME') \
+    local ms = string.format('%d', tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)) \
+    redis.call('XADD', KEYS[2], 'MAXLEN', '~', ARGV[2], seq .. '-' .. ms, 'seq', seq, 'binary', ARGV[4], 'payload', ARGV[1]) \
+    local min_ms = math.max(0, tonumber(now[1]) - tonumber(ARGV[3])) * 1000 \
+    while true do \
+        local oldest = redis.call('XRANGE', KEYS[2], '-', '+', 'COUNT', 1)[1] \
+        if not oldest or tonumber(string.match(oldest[1], '%-(%d+)$')) >= min_ms then break end \
+        redis.call('XDEL', KEYS[2], oldest[1]) \
+    end \
+    redis.call('PEXPIRE', KEYS[1], tonumber(ARGV[3]) * 1000) \
+    redis.call('PEXPIRE', KEYS[2], tonumber(ARGV[3]) * 1000) \
+    return tonumber(seq)";
 
-    // successful operations return an integer: 1 for added and 0 for already added
-    if res.iter().all(|res| match res {
-        Ok(RespValue::Integer(_)) => true,
-        _ => false
-    }) {
-        Ok(HttpResponse::Ok().finish())
+// reads the history of a topic after a sequence number, leaving out the entries older than the configured age which are
+// only trimmed on the next append, and reading the whole history when the sequence number is ahead of a restarted sequence
+const READ_HISTORY_SCRIPT: &str = "local since = tonumber(ARGV[1]) \
+    if since > tonumber(redis.call('GET', KEYS[1]) or '0') then since = 0 end \
+    local now = redis.call('TIME') \
+    local min_ms = math.max(0, tonumber(now[1]) - tonumber(ARGV[2])) * 1000 \
+    local entries = {} \
+    for _, entry in ipairs(redis.call('XRANGE', KEYS[2], string.format('%d', since + 1) .. '-0', '+')) do \
+        if tonumber(string.match(entry[1], '%-(%d+)$')) >= min_ms then table.insert(entries, entry) end \
+    end \
+    return entries";
+
+// registers the instance and renews its lease, returning 1 when the instance was not registered
+const HEARTBEAT_SCRIPT: &str = "local added = redis.call('SADD', KEYS[1], ARGV[1]) \
+    redis.call('SET', KEYS[2], '1', 'EX', ARGV[2]) \
+    return added";
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since_seq: Option<u64>
+}
+
+#[derive(Deserialize)]
+struct PublishQuery {
+    #[serde(default)]
+    report: bool
+}
+
+#[derive(Deserialize)]
+struct PublishRequest {
+    topic: String,
+    payload: serde_json::Value
+}
+
+// an event of a batch once appended to the history of its topic
+struct StampedEvent {
+    topic: String,
+    seq: u64,
+    payload: serde_json::Value,
+    // the payload as written to the streams of flight instances
+    bytes: Bytes
+}
+
+fn remove_target_command(topic: &str, str_info: String) -> Command {
+    if topic::is_pattern(topic) {
+        patterns::remove_target_command(topic, str_info)
     } else {
-        Ok(HttpResponse::InternalServerError().finish())
+        Command(resp_array!["SREM", format!("topic:{}", topic), str_info])
+    }
+}
+
+async fn subscribe(_: AuthorizedReq, path: web::Path<(String,)>, info: web::Query<TargetInfo>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let (topic,) = path.into_inner();
+    let str_info = info.into_inner().to_string();
+
+    let command = Command(resp_array![
+        "EVAL", SUBSCRIBE_SCRIPT, "4", format!("topic:{}", topic), format!("subscription:{}", str_info),
+        patterns::PATTERNS_KEY, patterns::PATTERNS_VERSION_KEY, str_info, topic.to_string(), if topic::is_pattern(&topic) { "1" } else { "0" }
+    ]);
+    match resp::send(&app_state.redis_addr, command).await {
+        Ok(_) => Ok(HttpResponse::Ok().finish()),
+        Err(_) => Ok(HttpResponse::InternalServerError().finish())
     }
 }
 
@@ -42,101 +124,433 @@ async fn unsubscribe(_: AuthorizedReq, path: web::Path<(String,)>, info: web::Qu
 
     let redis = &app_state.redis_addr;
 
-    let one = redis.send(Command(resp_array!["SREM", format!("topic:{}", topic), str_info.to_string()]));
-    let two = redis.send(Command(resp_array!["SREM", format!("subscription:{}", str_info), topic]));
+    let one = resp::send(redis, remove_target_command(&topic, str_info.to_string()));
+    let two = resp::send(redis, Command(resp_array!["SREM", format!("subscription:{}", str_info), topic]));
 
-    let res: Vec<Result<RespValue>> =
-        join_all(vec![one, two].into_iter())
-            .await
-            .into_iter()
-            .map(|item| {
-                item.map_err(Error::from)
-                    .and_then(|res| res.map_err(Error::from))
-            })
-            .collect();
+    let res: Vec<Result<RespValue>> = join_all(vec![one, two]).await;
 
    // successful operations return an integer: 1 for removed and 0 for already removed
-    if res.iter().all(|res| match res {
-        Ok(RespValue::Integer(_)) => true,
-        _ => false
-    }) {
+    if res.iter().all(|res| matches!(res, Ok(RespValue::Integer(_)))) {
         Ok(HttpResponse::Ok().finish())
     } else {
         Ok(HttpResponse::InternalServerError().finish())
     }
 }
 
-async fn remove_from_topic(str_info: String, topic: RespValue, redis: &actix::Addr<actix_redis::RedisActor>) -> Result<()> {
-    match topic {
-        RespValue::SimpleString(topic) => {
-            redis.send(Command(resp_array!["SREM", format!("topic:{}", topic), str_info])).await??;
-            Ok(())
-        }
-        RespValue::BulkString(vec) => {
-            let topic = std::str::from_utf8(&vec).map_err(|_| Error::InternalError)?;
-            redis.send(Command(resp_array!["SREM", format!("topic:{}", topic), str_info])).await??;
-            Ok(())
-        }
-        _ => Err(Error::InternalError)
-    }
+/// Removes every subscription of a flight instance and forgets the instance, in a single script
+/// so that a subscription made meanwhile is either removed entirely or kept entirely.
+pub async fn purge_target(str_info: &str, redis: &actix::Addr<actix_redis::RedisActor>) -> Result<()> {
+    let command = Command(resp_array![
+        "EVAL", PURGE_TARGET_SCRIPT, "5", format!("subscription:{}", str_info), INSTANCES_KEY, format!("lease:{}", str_info),
+        patterns::PATTERNS_KEY, patterns::PATTERNS_VERSION_KEY, str_info
+    ]);
+    resp::send(redis, command).await?;
+    Ok(())
 }
 
 async fn reset(_: AuthorizedReq, info: web::Query<TargetInfo>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
     let str_info = info.into_inner().to_string();
+    purge_target(&str_info, &app_state.redis_addr).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn heartbeat(_: AuthorizedReq, info: web::Query<TargetInfo>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let str_info = info.into_inner().to_string();
 
     let redis = &app_state.redis_addr;
+    let command = Command(resp_array![
+        "EVAL", HEARTBEAT_SCRIPT, "2", INSTANCES_KEY, format!("lease:{}", str_info),
+        str_info.to_string(), app_state.lease.ttl_seconds.to_string()
+    ]);
 
-    match redis.send(Command(resp_array!["SMEMBERS", format!("subscription:{}", str_info)])).await? {
-        Ok(RespValue::Array(topics)) => {
-            redis.send(Command(resp_array!["DEL", format!("subscription:{}", str_info)])).await??; 
+    // an unknown instance has been purged, or never registered, and has to subscribe to its topics again
+    match resp::send(redis, command).await? {
+        RespValue::Integer(1) => Ok(HttpResponse::Created().finish()),
+        RespValue::Integer(_) => Ok(HttpResponse::Ok().finish()),
+        _ => Ok(HttpResponse::InternalServerError().finish())
+    }
+}
 
-            let sends =  topics.into_iter().map(|topic| {
-                remove_from_topic(str_info.to_string(), topic, redis)
-            });
-            join_all(sends).await;
-            Ok(HttpResponse::Ok().finish())
+async fn append_history(topic: &str, req_body: &[u8], binary: bool, redis: &actix::Addr<actix_redis::RedisActor>, history: &HistoryConfig) -> Result<u64> {
+    let command = Command(resp_array![
+        "EVAL", APPEND_HISTORY_SCRIPT, "2", format!("seq:{}", topic), format!("history:{}", topic),
+        req_body.to_vec(), history.max_len.to_string(), history.ttl_seconds.to_string(), if binary { "1" } else { "0" }
+    ]);
+    match resp::send(redis, command).await? {
+        RespValue::Integer(seq) => Ok(seq as u64),
+        _ => Err(Error::InternalError)
+    }
+}
+
+async fn get_history(_: AuthorizedReq, path: web::Path<(String,)>, query: web::Query<HistoryQuery>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let (topic,) = path.into_inner();
+    let since_seq = query.into_inner().since_seq.unwrap_or(0);
+
+    let redis = &app_state.redis_addr;
+
+    // entries are stored under `{seq}-{ms}` ids, so the range starts right after the given sequence number
+    let command = Command(resp_array![
+        "EVAL", READ_HISTORY_SCRIPT, "2", format!("seq:{}", topic), format!("history:{}", topic),
+        since_seq.to_string(), app_state.history.ttl_seconds.to_string()
+    ]);
+    match resp::send(redis, command).await? {
+        RespValue::Array(entries) => {
+            let events: Vec<HistoryEntry> = entries.into_iter()
+                .filter_map(|entry| HistoryEntry::try_from(entry).ok())
+                .collect();
+            Ok(HttpResponse::Ok().json(events))
         }
-        _ => {
-            Ok(HttpResponse::Ok().finish())    
+        _ => Ok(HttpResponse::Ok().json(Vec::<HistoryEntry>::new()))
+    }
+}
+
+async fn fetch_presence(target: RespValue, topic: &str, app_state: &AppState) -> Result<Presence> {
+    let target = TargetInfo::try_from(target)?;
+    let url = format!("http://{}/api/v1/presence/{}", target, topic);
+    let body = app_state.http_client
+        .get(&url)
+        .basic_auth(&app_state.auth.username, Some(&app_state.auth.password))
+        .send().await?
+        .error_for_status()?
+        .text().await?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+async fn get_presence(_: AuthorizedReq, path: web::Path<(String,)>, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let (topic,) = path.into_inner();
+    if topic::is_pattern(&topic) {
+        return Err(Error::BadRequest);
+    }
+
+    // only the instances subscribed to the topic itself hold sockets present in it
+    let redis = &app_state.redis_addr;
+    let targets = match resp::send(redis, Command(resp_array!["SMEMBERS", format!("topic:{}", topic)])).await? {
+        RespValue::Array(targets) => targets,
+        _ => Vec::new()
+    };
+
+    let fetches = targets.into_iter().map(|target| fetch_presence(target, &topic, &app_state));
+    let mut count = 0;
+    let mut users = BTreeSet::new();
+    for presence in join_all(fetches).await {
+        match presence {
+            Ok(presence) => {
+                count += presence.count;
+                users.extend(presence.users);
+            }
+            Err(error) => log::warn!("Unable to fetch presence of {}: {}", topic, error)
         }
     }
+
+    Ok(HttpResponse::Ok().json(Presence {
+        count,
+        users: users.into_iter().collect()
+    }))
 }
 
-async fn send_event(req_body: String, target: RespValue, topic: String, http_client: &reqwest::Client, auth: &AuthInfo) -> Result<()> {
-    if let Ok(target) = TargetInfo::try_from(target) {
-        let url = format!("http://{}/api/v1/event/{}", target, topic);
-        let auth = auth.clone();
-        let res = http_client
-            .post(&url)
-            .basic_auth(auth.username, Some(auth.password))
-            .body(req_body).header("content-type", "application/json")
-            .send().await?;
+/// Sends the event to a flight instance, returning the number of sockets it was delivered to,
+/// which is only known when the event is not written to a stream. The event carries the trace context of the delivery.
+async fn send_event(event: LinkEvent, target: Target, report: bool, app_state: &AppState, cx: &Context) -> Result<usize> {
+    let failing = target.failing;
+    let target = TargetInfo::try_from(target.info)?.to_string();
+    if failing {
+        app_state.health.suspect(&target);
+    }
+    let cx = telemetry::span("send_event", SpanKind::Client, cx, vec![KeyValue::new("target", target.to_string())]);
+    let event = LinkEvent { traceparent: telemetry::traceparent(&cx), ..event };
 
-        if res.status() != reqwest::StatusCode::OK {
-            log::error!("Unable to send request to {}", target);
+    // prefer the persistent stream and fall back to a plain HTTP request when it is unavailable,
+    // while reported publishes wait for flight to acknowledge the event
+    let event = match &app_state.links {
+        Some(links) if !report => match links.try_send(&target, event) {
+            Ok(()) => return Ok(0),
+            Err(event) => event
+        },
+        _ => event
+    };
+    let result = post_event(&target, event, &app_state.http_client, &app_state.auth).await;
+    app_state.health.record(&target, result.is_ok(), app_state.links.as_ref()).await?;
+    result
+}
+
+/// Sends the events of a batch to a flight instance, over its stream like single events when possible,
+/// and with a single request otherwise.
+async fn send_events(target: String, events: Vec<&StampedEvent>, report: bool, app_state: &AppState, cx: &Context) -> Result<usize> {
+    let cx = telemetry::span("send_events", SpanKind::Client, cx, vec![
+        KeyValue::new("target", target.to_string()),
+        KeyValue::new("events", events.len() as i64)
+    ]);
+
+    // the events the stream cannot take are sent together
+    let events: Vec<&StampedEvent> = match &app_state.links {
+        Some(links) if !report => {
+            let traceparent = telemetry::traceparent(&cx);
+            events.into_iter()
+                .filter(|event| {
+                    let event = LinkEvent {
+                        topic: event.topic.to_string(),
+                        seq: event.seq,
+                        payload: event.bytes.clone(),
+                        binary: false,
+                        traceparent: traceparent.clone()
+                    };
+                    links.try_send(&target, event).is_err()
+                })
+                .collect()
         }
+        _ => events
+    };
+    if events.is_empty() {
+        return Ok(0);
     }
-    Ok(())
+
+    let events: Vec<BatchEvent> = events.into_iter()
+        .map(|event| BatchEvent {
+            topic: event.topic.to_string(),
+            seq: event.seq,
+            payload: event.payload.clone()
+        })
+        .collect();
+    let result = post_events(&target, &events, telemetry::traceparent(&cx), &app_state.http_client, &app_state.auth).await;
+    app_state.health.record(&target, result.is_ok(), app_state.links.as_ref()).await?;
+    result
 }
 
-async fn publish_event(_: AuthorizedReq, path: web::Path<(String,)>, req_body: String, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+// anything but JSON is delivered to sockets as it is, in binary frames
+fn is_binary(req: &HttpRequest) -> bool {
+    match req.headers().get("content-type").and_then(|value| value.to_str().ok()) {
+        Some(content_type) => !content_type.starts_with(JSON_CONTENT_TYPE),
+        None => true
+    }
+}
+
+// publishes are only acknowledged with a body when a report is asked for
+fn publish_response(report: bool, publish_report: PublishReport) -> HttpResponse {
+    if report {
+        HttpResponse::Ok().json(publish_report)
+    } else {
+        HttpResponse::NoContent().finish()
+    }
+}
+
+async fn publish_event(_: AuthorizedReq, req: HttpRequest, path: web::Path<(String,)>, query: web::Query<PublishQuery>, req_body: Bytes, app_state: web::Data<AppState>) -> Result<HttpResponse> {
     let (topic,) = path.into_inner();
+    let report = query.into_inner().report;
+    if topic::is_pattern(&topic) {
+        return Err(Error::BadRequest);
+    }
     
+    let cx = telemetry::span("publish_event", SpanKind::Server, &telemetry::extract(req.headers()), vec![KeyValue::new("topic", topic.to_string())]);
+    let binary = is_binary(&req);
     let redis = &app_state.redis_addr;
 
-    match redis.send(Command(resp_array!["SMEMBERS", format!("topic:{}", topic)])).await? {
-        Ok(RespValue::Array(targets)) => {
+    // the targets are looked up along with the history append, so that both share a round trip to Redis
+    let append = async {
+        let _cx = telemetry::span("append_history", SpanKind::Client, &cx, Vec::new());
+        append_history(&topic, &req_body, binary, redis, &app_state.history).await
+    };
+    let lookup = async {
+        if app_state.delivery_mode == DeliveryMode::Redis {
+            return None;
+        }
+        let _cx = telemetry::span("get_targets", SpanKind::Client, &cx, Vec::new());
+        Some(app_state.patterns.targets(&[&topic]).await.and_then(|mut targets| targets.remove(0)))
+    };
+    let (seq, targets) = join(append, lookup).await;
+    let seq = seq?;
+    metrics().record_publish(&topic);
+
+    // flight instances listen on the channel themselves, so there are no targets to resolve
+    if app_state.delivery_mode == DeliveryMode::Redis {
+        let cx = telemetry::span("publish_channel", SpanKind::Producer, &cx, Vec::new());
+        let frame = frame::encode_event(&topic, seq, &req_body, binary, telemetry::traceparent(&cx).as_deref()).ok_or(Error::BadRequest)?;
+        // every flight instance listening on the channel receives the event, which it does not acknowledge
+        let receivers = match resp::send(redis, Command(resp_array!["PUBLISH", format!("{}{}", EVENT_CHANNEL_PREFIX, topic), frame.to_vec()])).await? {
+            RespValue::Integer(receivers) => receivers as usize,
+            _ => 0
+        };
+        return Ok(publish_response(report, PublishReport {
+            targeted: receivers,
+            succeeded: None,
+            failed: None,
+            sockets: None
+        }));
+    }
+
+    match targets {
+        Some(Ok(targets)) => {
+            metrics().fanout_size.observe(targets.len() as f64);
+            let event = LinkEvent { topic: topic.to_string(), seq, payload: req_body, binary, traceparent: None };
             let sends =  targets.into_iter().map(|target| {
-                send_event(req_body.to_string(), target, topic.to_string(), &app_state.http_client, &app_state.auth)
+                send_event(event.clone(), target, report, &app_state, &cx)
             });
-            join_all(sends).await;
-            Ok(HttpResponse::NoContent().finish())
+
+            let results = join_all(sends).await;
+            let delivered: Vec<usize> = results.iter().filter_map(|result| result.as_ref().ok().copied()).collect();
+            Ok(publish_response(report, PublishReport {
+                targeted: results.len(),
+                succeeded: Some(delivered.len()),
+                failed: Some(results.len() - delivered.len()),
+                sockets: Some(delivered.iter().sum())
+            }))
         }
-        Ok(RespValue::Nil) => Ok(HttpResponse::NoContent().finish()),
         _ => Ok(HttpResponse::InternalServerError().finish())
     }    
 }
 
+// accepts either a JSON array of events or one event per line, parsing the lines as they arrive
+async fn parse_batch(req: &HttpRequest, mut body: web::Payload) -> Result<Vec<PublishRequest>> {
+    let ndjson = req.headers().get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with(NDJSON_CONTENT_TYPE));
+
+    let mut buffer = BytesMut::new();
+    let mut received = 0;
+    let mut requests = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        received += chunk.len();
+        if received > BATCH_PAYLOAD_LIMIT {
+            return Err(Error::PayloadTooLarge);
+        }
+        buffer.extend_from_slice(&chunk);
+
+        // only the line still being received is kept
+        if ndjson {
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line = buffer.split_to(end + 1);
+                parse_line(&line, &mut requests)?;
+            }
+        }
+    }
+
+    if !ndjson {
+        return Ok(serde_json::from_slice(&buffer)?);
+    }
+    parse_line(&buffer, &mut requests)?;
+    Ok(requests)
+}
+
+fn parse_line(line: &[u8], requests: &mut Vec<PublishRequest>) -> Result<()> {
+    if line.iter().any(|byte| !byte.is_ascii_whitespace()) {
+        requests.push(serde_json::from_slice(line)?);
+    }
+    Ok(())
+}
+
+async fn publish_events(_: AuthorizedReq, req: HttpRequest, query: web::Query<PublishQuery>, body: web::Payload, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let report = query.into_inner().report;
+    let requests = parse_batch(&req, body).await?;
+    if requests.iter().any(|request| topic::is_pattern(&request.topic)) {
+        return Err(Error::BadRequest);
+    }
+
+    let cx = telemetry::span("publish_events", SpanKind::Server, &telemetry::extract(req.headers()), vec![KeyValue::new("events", requests.len() as i64)]);
+    let redis = &app_state.redis_addr;
+    let payloads = requests.iter()
+        .map(|request| serde_json::to_vec(&request.payload).map(Bytes::from))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    // commands sent together are pipelined on the connection to Redis rather than waiting for each other,
+    // so the targets are looked up along with the history appends
+    let appends = async {
+        let _cx = telemetry::span("append_history", SpanKind::Client, &cx, Vec::new());
+        let appends = requests.iter().zip(&payloads)
+            .map(|(request, payload)| append_history(&request.topic, payload, false, redis, &app_state.history));
+        join_all(appends).await.into_iter().collect::<Result<Vec<u64>>>()
+    };
+    let lookup = async {
+        if app_state.delivery_mode == DeliveryMode::Redis {
+            return Ok(Vec::new());
+        }
+        let _cx = telemetry::span("get_targets", SpanKind::Client, &cx, Vec::new());
+        let topics: Vec<&str> = requests.iter().map(|request| request.topic.as_str()).collect();
+        app_state.patterns.targets(&topics).await
+    };
+    let (seqs, targets) = join(appends, lookup).await;
+    let seqs = seqs?;
+    for request in &requests {
+        metrics().record_publish(&request.topic);
+    }
+    let events: Vec<StampedEvent> = requests.into_iter().zip(payloads).zip(seqs)
+        .map(|((request, bytes), seq)| StampedEvent {
+            topic: request.topic,
+            seq,
+            payload: request.payload,
+            bytes
+        })
+        .collect();
+
+    if app_state.delivery_mode == DeliveryMode::Redis {
+        let cx = telemetry::span("publish_channel", SpanKind::Producer, &cx, Vec::new());
+        let traceparent = telemetry::traceparent(&cx);
+        let publishes = events.iter()
+            .map(|event| {
+                let frame = frame::encode_event(&event.topic, event.seq, &event.bytes, false, traceparent.as_deref()).ok_or(Error::BadRequest)?;
+                Ok(Command(resp_array!["PUBLISH", format!("{}{}", EVENT_CHANNEL_PREFIX, event.topic), frame.to_vec()]))
+            })
+            .collect::<Result<Vec<Command>>>()?;
+        let mut receivers = 0;
+        for result in join_all(publishes.into_iter().map(|command| resp::send(redis, command))).await {
+            if let RespValue::Integer(count) = result? {
+                receivers += count as usize;
+            }
+        }
+        return Ok(publish_response(report, PublishReport {
+            targeted: receivers,
+            succeeded: None,
+            failed: None,
+            sockets: None
+        }));
+    }
+
+    let targets = targets?;
+
+    // the events are part of the history already, so the ones whose targets cannot be resolved are skipped
+    // rather than failing the batch, which publishers would retry
+    let mut batches: HashMap<String, Vec<&StampedEvent>> = HashMap::new();
+    for (event, targets) in events.iter().zip(targets) {
+        let targets = match targets {
+            Ok(targets) => targets,
+            Err(error) => {
+                log::error!("Unable to look up the targets of topic {}: {}", event.topic, error);
+                Vec::new()
+            }
+        };
+        metrics().fanout_size.observe(targets.len() as f64);
+        for target in targets {
+            let failing = target.failing;
+            match TargetInfo::try_from(target.info) {
+                Ok(target) => {
+                    let target = target.to_string();
+                    if failing {
+                        app_state.health.suspect(&target);
+                    }
+                    batches.entry(target).or_default().push(event);
+                }
+                Err(error) => log::warn!("Skipping an invalid target of topic {}: {}", event.topic, error)
+            }
+        }
+    }
+
+    let sends = batches.into_iter().map(|(target, events)| {
+        let count = events.len();
+        let send = send_events(target, events, report, &app_state, &cx);
+        async move { (count, send.await) }
+    });
+    let results = join_all(sends).await;
+
+    // every event counts once for every flight instance it was sent to
+    let targeted = results.iter().map(|(count, _)| count).sum();
+    let succeeded = results.iter().filter(|(_, result)| result.is_ok()).map(|(count, _)| count).sum();
+    Ok(publish_response(report, PublishReport {
+        targeted,
+        succeeded: Some(succeeded),
+        failed: Some(targeted - succeeded),
+        sockets: Some(results.iter().filter_map(|(_, result)| result.as_ref().ok()).sum())
+    }))
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service( 
         web::resource("/subscription/{topic:.*}")
@@ -145,9 +559,39 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     ).service(
         web::resource("/subscription")
             .route(web::delete().to(reset))
+    ).service(
+        web::resource("/presence/{topic:.*}")
+            .route(web::get().to(get_presence))
+    ).service(
+        web::resource("/heartbeat")
+            .route(web::put().to(heartbeat))
+    ).service(
+        web::resource("/history/{topic:.*}")
+            .route(web::get().to(get_history))
     ).service(
         web::resource("/event/{topic:.*}")
-            .guard(guard::Header("content-type", "application/json"))
             .route(web::post().to(publish_event))
+    ).service(
+        web::resource("/events")
+            .route(web::post().to(publish_events))
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_batch_lines() {
+        let mut requests = Vec::new();
+        parse_line(b"{\"topic\":\"news\",\"payload\":{\"message\":\"hello\"}}\n", &mut requests).unwrap();
+        parse_line(b" \r\n", &mut requests).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].topic, "news");
+        assert_eq!(requests[0].payload, serde_json::json!({ "message": "hello" }));
+
+        assert!(parse_line(b"{\"topic\":\"news\"}\n", &mut requests).is_err());
+        assert!(parse_line(b"not json\n", &mut requests).is_err());
+        assert_eq!(requests.len(), 1);
+    }
+}
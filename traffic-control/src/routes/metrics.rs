@@ -0,0 +1,29 @@
+use actix_redis::Command;
+use actix_web::{web, HttpResponse};
+use redis_async::{resp::RespValue, resp_array};
+
+use crate::{
+    routes::api::v1::INSTANCES_KEY,
+    types::{Result, AppState, AuthorizedReq},
+    utils::{resp, metrics::metrics}
+};
+
+pub async fn index(_: AuthorizedReq, app_state: web::Data<AppState>) -> Result<HttpResponse> {
+    let redis = &app_state.redis_addr;
+    let metrics = metrics();
+
+    // subscription sets are read at scrape time, so that purged instances disappear from the metrics
+    metrics.subscriptions.reset();
+    if let RespValue::Array(instances) = resp::send(redis, Command(resp_array!["SMEMBERS", INSTANCES_KEY])).await? {
+        for instance in instances {
+            let target = resp::to_string(instance)?;
+            if let RespValue::Integer(size) = resp::send(redis, Command(resp_array!["SCARD", format!("subscription:{}", target)])).await? {
+                metrics.subscriptions.with_label_values(&[&target]).set(size);
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.encode()))
+}
@@ -1 +1,2 @@
-pub mod api;
\ No newline at end of file
+pub mod api;
+pub mod metrics;
\ No newline at end of file
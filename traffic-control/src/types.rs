@@ -1,29 +1,64 @@
-use actix::{
-    Message,
-    prelude::Addr
-};
+use actix::prelude::Addr;
 use actix_redis::RedisActor;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use crate::error::FlockError;
 
 pub use crate::resources::target_info::TargetInfo;
+pub use crate::resources::history::HistoryEntry;
+pub use crate::resources::flight_link::FlightLinks;
+pub use crate::resources::target_health::TargetHealth;
+pub use crate::resources::patterns::Patterns;
 pub use crate::utils::auth::AuthorizedReq;
-pub use crate::utils::env::AuthInfo;
+pub use crate::utils::env::{AuthInfo, HistoryConfig, LeaseConfig, DeliveryMode};
 
-#[derive(Deserialize)]
-pub struct Event {
+pub const SEQUENCE_HEADER: &str = "Flock-Sequence";
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+pub const EVENT_CHANNEL_PREFIX: &str = "event:";
+
+/// Sockets subscribed to a topic, along with the subjects of the authenticated ones.
+#[derive(Serialize, Deserialize)]
+pub struct Presence {
+    pub count: usize,
+    pub users: Vec<String>
+}
+
+/// Outcome of a publish, returned when it is made with `?report=true`.
+#[derive(Serialize)]
+pub struct PublishReport {
+    // flight instances the event was sent to
+    pub targeted: usize,
+    // acknowledgements, sockets reached included, are unknown when flight instances receive events from Redis
+    pub succeeded: Option<usize>,
+    pub failed: Option<usize>,
+    pub sockets: Option<usize>
+}
+
+/// A JSON event of a batch, as sent to the batch route of flight.
+#[derive(Serialize)]
+pub struct BatchEvent {
     pub topic: String,
-    pub message: String
+    pub seq: u64,
+    pub payload: serde_json::Value
 }
 
-impl Message for Event {
-    type Result = Result<bool>;
+/// Reply of the event route of flight.
+#[derive(Deserialize)]
+pub struct Delivery {
+    // local sockets the event was handed to
+    pub delivered: usize
 }
 
 pub struct AppState {
     pub redis_addr: Addr<RedisActor>,
     pub http_client: reqwest::Client,
-    pub auth: AuthInfo
+    pub auth: AuthInfo,
+    pub history: HistoryConfig,
+    pub delivery_mode: DeliveryMode,
+    pub lease: LeaseConfig,
+    pub health: TargetHealth,
+    pub patterns: Patterns,
+    pub links: Option<FlightLinks>
 }
 
 pub type Error = FlockError;
@@ -9,8 +9,22 @@ const DEFAULT_PASSWORD: &str = "password";
 const DEFAULT_REDIS_HOST: &str = "localhost";
 const DEFAULT_REDIS_PORT: u16 = 6379;
 
+const DEFAULT_HISTORY_MAX_LEN: u64 = 100;
+const DEFAULT_HISTORY_TTL_SECONDS: u64 = 3600;
+
+const DEFAULT_LINK_CAPACITY: usize = 1024;
+
+const DEFAULT_LEASE_TTL_SECONDS: u64 = 30;
+const DEFAULT_REAP_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_MAX_DELIVERY_FAILURES: u32 = 3;
+
+const DEFAULT_METRICS_MAX_TOPICS: usize = 100;
+
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_URLS: &str = "http://localhost:8088";
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_USERNAME: &str = "admin";
+#[cfg(feature = "watchtower")]
 const DEFAULT_WATCHTOWER_PASSWORD: &str = "password";
 
 #[derive(Serialize)]
@@ -39,12 +53,14 @@ pub fn get_redis_info() -> InstanceInfo {
     }
 }
 
+#[cfg(feature = "watchtower")]
 pub struct WatchTowerConfig {
     pub urls: Vec<String>,
     pub username: String,
     pub password: String
 }
 
+#[cfg(feature = "watchtower")]
 pub fn get_watchtower_config() -> WatchTowerConfig {
     WatchTowerConfig {
         urls: std::env::var("WATCHTOWER_URLS").unwrap_or(DEFAULT_WATCHTOWER_URLS.to_string()).split(',').map(|item| item.to_string()).collect(),
@@ -53,6 +69,34 @@ pub fn get_watchtower_config() -> WatchTowerConfig {
     }
 }
 
+// watchtower is only the default when it is built in
+#[cfg(feature = "watchtower")]
+const DEFAULT_DISCOVERY_MODE: DiscoveryMode = DiscoveryMode::Watchtower;
+#[cfg(not(feature = "watchtower"))]
+const DEFAULT_DISCOVERY_MODE: DiscoveryMode = DiscoveryMode::Static;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiscoveryMode {
+    /// Registers the instance with watchtower.
+    #[cfg(feature = "watchtower")]
+    Watchtower,
+    /// Registers nowhere, leaving the instance to be listed in `TRAFFIC_CONTROL_URLS` of flight.
+    Static,
+    /// Registers nowhere, leaving the instance to be listed in DNS records.
+    Dns,
+    /// Registers the instance in Redis.
+    Redis
+}
+
+pub fn get_discovery_mode() -> DiscoveryMode {
+    match std::env::var("DISCOVERY").map(|mode| mode.to_lowercase()) {
+        Ok(mode) if mode == "static" => DiscoveryMode::Static,
+        Ok(mode) if mode == "dns" => DiscoveryMode::Dns,
+        Ok(mode) if mode == "redis" => DiscoveryMode::Redis,
+        _ => DEFAULT_DISCOVERY_MODE
+    }
+}
+
 #[derive(Clone)]
 pub struct AuthInfo {
     pub username: String,
@@ -65,3 +109,84 @@ pub fn get_auth_info() -> AuthInfo {
         password: std::env::var("FLOCK_PASSWORD").unwrap_or(DEFAULT_PASSWORD.to_string()), 
     }
 }
+
+#[derive(Clone)]
+pub struct HistoryConfig {
+    pub max_len: u64,
+    pub ttl_seconds: u64
+}
+
+pub fn get_history_config() -> HistoryConfig {
+    HistoryConfig {
+        max_len: match std::env::var("HISTORY_MAX_LEN") {
+            Ok(max_len) => max_len.parse::<u64>().unwrap_or(DEFAULT_HISTORY_MAX_LEN),
+            _ => DEFAULT_HISTORY_MAX_LEN
+        },
+        ttl_seconds: match std::env::var("HISTORY_TTL_SECONDS") {
+            Ok(ttl) => ttl.parse::<u64>().unwrap_or(DEFAULT_HISTORY_TTL_SECONDS),
+            _ => DEFAULT_HISTORY_TTL_SECONDS
+        }
+    }
+}
+
+pub struct LinkConfig {
+    pub enabled: bool,
+    pub capacity: usize
+}
+
+pub fn get_link_config() -> LinkConfig {
+    LinkConfig {
+        enabled: std::env::var("FLIGHT_LINK_ENABLED").map(|enabled| enabled.to_lowercase() != "false").unwrap_or(true),
+        capacity: match std::env::var("FLIGHT_LINK_CAPACITY") {
+            Ok(capacity) => capacity.parse::<usize>().unwrap_or(DEFAULT_LINK_CAPACITY),
+            _ => DEFAULT_LINK_CAPACITY
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LeaseConfig {
+    pub ttl_seconds: u64,
+    pub reap_interval_seconds: u64,
+    pub max_failures: u32
+}
+
+pub fn get_lease_config() -> LeaseConfig {
+    LeaseConfig {
+        ttl_seconds: match std::env::var("FLIGHT_LEASE_TTL_SECONDS") {
+            Ok(ttl) => ttl.parse::<u64>().unwrap_or(DEFAULT_LEASE_TTL_SECONDS),
+            _ => DEFAULT_LEASE_TTL_SECONDS
+        },
+        reap_interval_seconds: match std::env::var("FLIGHT_REAP_INTERVAL_SECONDS") {
+            Ok(interval) => interval.parse::<u64>().unwrap_or(DEFAULT_REAP_INTERVAL_SECONDS),
+            _ => DEFAULT_REAP_INTERVAL_SECONDS
+        },
+        max_failures: match std::env::var("FLIGHT_MAX_DELIVERY_FAILURES") {
+            Ok(max_failures) => max_failures.parse::<u32>().unwrap_or(DEFAULT_MAX_DELIVERY_FAILURES),
+            _ => DEFAULT_MAX_DELIVERY_FAILURES
+        }
+    }
+}
+
+/// Number of topics whose publishes are counted under their own name on `/metrics`.
+pub fn get_metrics_max_topics() -> usize {
+    match std::env::var("METRICS_MAX_TOPICS") {
+        Ok(count) => count.parse::<usize>().unwrap_or(DEFAULT_METRICS_MAX_TOPICS),
+        _ => DEFAULT_METRICS_MAX_TOPICS
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeliveryMode {
+    /// Traffic-control resolves the subscribed flight instances and sends them each event.
+    Http,
+    /// Traffic-control publishes each event on a Redis channel that flight instances subscribe to.
+    Redis
+}
+
+pub fn get_delivery_mode() -> DeliveryMode {
+    match std::env::var("DELIVERY_MODE") {
+        Ok(mode) if mode.to_lowercase() == "redis" => DeliveryMode::Redis,
+        _ => DeliveryMode::Http
+    }
+}
@@ -0,0 +1,38 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+pub const EVENT_FRAME: u8 = 1;
+pub const BINARY_EVENT_FRAME: u8 = 2;
+// set on the kind of frames carrying the trace context of the event
+pub const TRACE_CONTEXT_FLAG: u8 = 0x80;
+
+const HEADER_LEN: usize = 11;
+
+/// Encodes an event as `kind (u8) | seq (u64) | topic length (u16) | topic | payload`
+/// with integers in big endian, where the kind tells JSON payloads from binary ones.
+/// With a trace context, the kind has `TRACE_CONTEXT_FLAG` set and the topic is followed by
+/// `traceparent length (u8) | traceparent`.
+/// Returns `None` when the topic is too long to be framed.
+pub fn encode_event(topic: &str, seq: u64, payload: &[u8], binary: bool, traceparent: Option<&str>) -> Option<Bytes> {
+    if topic.len() > u16::MAX as usize {
+        return None;
+    }
+    let traceparent = traceparent.filter(|traceparent| traceparent.len() <= u8::MAX as usize);
+
+    let mut kind = if binary { BINARY_EVENT_FRAME } else { EVENT_FRAME };
+    if traceparent.is_some() {
+        kind |= TRACE_CONTEXT_FLAG;
+    }
+
+    let trace_len = traceparent.map_or(0, |traceparent| 1 + traceparent.len());
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + topic.len() + trace_len + payload.len());
+    buf.put_u8(kind);
+    buf.put_u64(seq);
+    buf.put_u16(topic.len() as u16);
+    buf.put_slice(topic.as_bytes());
+    if let Some(traceparent) = traceparent {
+        buf.put_u8(traceparent.len() as u8);
+        buf.put_slice(traceparent.as_bytes());
+    }
+    buf.put_slice(payload);
+    Some(buf.freeze())
+}
@@ -0,0 +1,115 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder
+};
+use std::{
+    collections::HashSet,
+    sync::Mutex
+};
+use crate::utils::env;
+
+// label of the publishes to topics beyond the tracked ones
+const OTHER_TOPICS: &str = "other";
+
+/// Transports events are sent to flight instances with.
+pub const HTTP_TRANSPORT: &str = "http";
+pub const STREAM_TRANSPORT: &str = "stream";
+
+/// Metrics of this instance, exposed in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    // topics with a publish series of their own, at most `max_topics` of them
+    topics: Mutex<HashSet<String>>,
+    max_topics: usize,
+    publishes: IntCounterVec,
+    pub fanout_size: Histogram,
+    pub send_latency: HistogramVec,
+    pub send_failures: IntCounterVec,
+    pub redis_latency: HistogramVec,
+    pub redis_errors: IntCounterVec,
+    pub subscriptions: IntGaugeVec
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new();
+    let metrics = Metrics {
+        topics: Mutex::new(HashSet::new()),
+        max_topics: env::get_metrics_max_topics(),
+        publishes: IntCounterVec::new(
+            Opts::new("traffic_control_publishes_total", "Events published per topic"),
+            &["topic"]
+        ).unwrap(),
+        fanout_size: Histogram::with_opts(
+            HistogramOpts::new("traffic_control_fanout_size", "Flight instances each event is sent to")
+                .buckets(vec![0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0])
+        ).unwrap(),
+        send_latency: HistogramVec::new(
+            HistogramOpts::new("traffic_control_send_latency_seconds", "Latency of sending events to flight instances"),
+            &["target", "transport"]
+        ).unwrap(),
+        send_failures: IntCounterVec::new(
+            Opts::new("traffic_control_send_failures_total", "Events that could not be sent to a flight instance"),
+            &["target", "transport"]
+        ).unwrap(),
+        redis_latency: HistogramVec::new(
+            HistogramOpts::new("traffic_control_redis_latency_seconds", "Latency of the Redis commands")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]),
+            &["command"]
+        ).unwrap(),
+        redis_errors: IntCounterVec::new(
+            Opts::new("traffic_control_redis_errors_total", "Redis commands that failed"),
+            &["command"]
+        ).unwrap(),
+        subscriptions: IntGaugeVec::new(
+            Opts::new("traffic_control_subscriptions", "Topics each flight instance is subscribed to"),
+            &["target"]
+        ).unwrap(),
+        registry
+    };
+
+    let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+        Box::new(metrics.publishes.clone()),
+        Box::new(metrics.fanout_size.clone()),
+        Box::new(metrics.send_latency.clone()),
+        Box::new(metrics.send_failures.clone()),
+        Box::new(metrics.redis_latency.clone()),
+        Box::new(metrics.redis_errors.clone()),
+        Box::new(metrics.subscriptions.clone())
+    ];
+    for collector in collectors {
+        metrics.registry.register(collector).unwrap();
+    }
+    metrics
+});
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    /// Counts a publish, under its own topic for the first `max_topics` topics and under `other` afterwards.
+    pub fn record_publish(&self, topic: &str) {
+        let tracked = {
+            let mut topics = self.topics.lock().unwrap();
+            if topics.contains(topic) {
+                true
+            } else if topics.len() < self.max_topics {
+                topics.insert(topic.to_string());
+                true
+            } else {
+                false
+            }
+        };
+        let label = if tracked { topic } else { OTHER_TOPICS };
+        self.publishes.with_label_values(&[label]).inc();
+    }
+
+    /// Encodes every metric in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(error) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::warn!("Failed to encode metrics: {}", error);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
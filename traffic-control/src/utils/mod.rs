@@ -1,2 +1,6 @@
 pub mod env;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod topic;
+pub mod resp;
+pub mod frame;
+pub mod metrics;
\ No newline at end of file
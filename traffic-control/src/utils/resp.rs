@@ -0,0 +1,42 @@
+use actix::Addr;
+use actix_redis::{Command, RedisActor};
+use redis_async::resp::RespValue;
+use crate::{
+    types::{Result, Error},
+    utils::metrics::metrics
+};
+
+pub fn to_string(value: RespValue) -> Result<String> {
+    match value {
+        RespValue::SimpleString(value) => Ok(value),
+        RespValue::BulkString(vec) => String::from_utf8(vec).map_err(|_| Error::InternalError),
+        _ => Err(Error::InternalError)
+    }
+}
+
+/// Sends a command to Redis, recording its latency and whether it failed, error replies included.
+pub async fn send(redis: &Addr<RedisActor>, command: Command) -> Result<RespValue> {
+    let name = match &command.0 {
+        RespValue::Array(values) => match values.first() {
+            Some(RespValue::BulkString(name)) => String::from_utf8_lossy(name).to_uppercase(),
+            _ => String::new()
+        },
+        _ => String::new()
+    };
+
+    let timer = metrics().redis_latency.with_label_values(&[&name]).start_timer();
+    let res = redis.send(command).await
+        .map_err(Error::from)
+        .and_then(|res| res.map_err(Error::from));
+    timer.observe_duration();
+
+    match &res {
+        Ok(RespValue::Error(error)) => {
+            log::warn!("Redis command {} failed: {}", name, error);
+            metrics().redis_errors.with_label_values(&[&name]).inc();
+        }
+        Err(_) => metrics().redis_errors.with_label_values(&[&name]).inc(),
+        _ => ()
+    }
+    res
+}
\ No newline at end of file
@@ -0,0 +1,21 @@
+pub const SEPARATOR: char = '.';
+pub const SINGLE_WILDCARD: &str = "*";
+pub const MULTI_WILDCARD: &str = ">";
+
+/// Returns true when the topic contains a `*` or `>` token.
+pub fn is_pattern(topic: &str) -> bool {
+    topic.split(SEPARATOR).any(|token| token == SINGLE_WILDCARD || token == MULTI_WILDCARD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_patterns_by_whole_tokens() {
+        assert!(is_pattern("news.*"));
+        assert!(is_pattern(">"));
+        assert!(!is_pattern("news.s*"));
+        assert!(!is_pattern("news.sport"));
+    }
+}
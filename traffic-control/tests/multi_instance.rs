@@ -0,0 +1,322 @@
+//! Runs two traffic-control processes against one Redis, given by `REDIS_HOST` and `REDIS_PORT`
+//! (`localhost:6379` by default). Events are published by a socket of a flight process, which spreads
+//! them over both instances, and received over HTTP by a fake flight instance.
+//! The flight binary is taken from the target directory of traffic-control, so build the workspace first
+//! and run with `cargo build --workspace && cargo test --workspace -- --ignored`.
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use awc::{BoxedSocket, ws::{Codec, Frame, Message}};
+use actix_codec::Framed;
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    collections::BTreeSet,
+    path::Path,
+    process::{Child, Command},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+const USERNAME: &str = "admin";
+const PASSWORD: &str = "password";
+
+// asks the system for a port nothing listens on, so that tests running in parallel or next to other services do not collide
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("failed to find a free port")
+        .port()
+}
+
+/// A traffic-control process, killed when dropped.
+struct TrafficControl {
+    child: Child,
+    base_url: String
+}
+
+impl TrafficControl {
+    fn spawn() -> TrafficControl {
+        let port = free_port();
+        let child = Command::new(env!("CARGO_BIN_EXE_traffic-control"))
+            .env("TRAFFIC_CONTROL_HOST", "127.0.0.1")
+            .env("TRAFFIC_CONTROL_PORT", port.to_string())
+            .env("FLIGHT_LINK_ENABLED", "false")
+            .env("DELIVERY_MODE", "http")
+            .env("DISCOVERY", "static")
+            .spawn()
+            .expect("failed to start traffic-control");
+        TrafficControl {
+            child,
+            base_url: format!("http://127.0.0.1:{}", port)
+        }
+    }
+}
+
+impl Drop for TrafficControl {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A flight process finding both traffic-control instances in its static configuration, killed when dropped.
+struct Flight {
+    child: Child,
+    ws_url: String,
+    metrics_url: String
+}
+
+impl Flight {
+    fn spawn(instances: &[&TrafficControl]) -> Flight {
+        let port = free_port();
+        let binary = Path::new(env!("CARGO_BIN_EXE_traffic-control")).with_file_name("flight");
+        assert!(binary.exists(), "{} is missing, build the workspace first", binary.display());
+        let urls: Vec<&str> = instances.iter()
+            .map(|instance| instance.base_url.trim_start_matches("http://"))
+            .collect();
+        let child = Command::new(binary)
+            .env("FLIGHT_HOST", "127.0.0.1")
+            .env("FLIGHT_PORT", port.to_string())
+            .env("DELIVERY_MODE", "http")
+            .env("DISCOVERY", "static")
+            .env("TRAFFIC_CONTROL_URLS", urls.join(","))
+            .env("WS_COMMAND_RATE_LIMIT", "100000")
+            // calls of the heartbeat would take turns with the publishes
+            .env("HEARTBEAT_INTERVAL_SECONDS", "3600")
+            .env_remove("FLIGHT_TOKEN_SECRET")
+            .spawn()
+            .expect("failed to start flight");
+        Flight {
+            child,
+            ws_url: format!("http://127.0.0.1:{}/ws", port),
+            metrics_url: format!("http://127.0.0.1:{}/metrics", port)
+        }
+    }
+
+    // connects a socket once flight is up
+    async fn connect(&self) -> Framed<BoxedSocket, Codec> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok((_, connection)) = awc::Client::new().ws(&self.ws_url).connect().await {
+                return connection;
+            }
+            actix::clock::delay_for(Duration::from_millis(100)).await;
+        }
+        panic!("{} did not start", self.ws_url);
+    }
+
+    /// Publishes events numbered by `numbers` through a socket, returning how many flight published.
+    async fn publish(&self, connection: &mut Framed<BoxedSocket, Codec>, topic: &str, numbers: std::ops::Range<usize>) -> usize {
+        let count = numbers.len();
+        for n in numbers {
+            let command = serde_json::json!({
+                "type": "Publish",
+                "topic": topic,
+                "payload": { "n": n },
+                "request_id": n.to_string()
+            });
+            connection.send(Message::Text(command.to_string())).await.expect("failed to send publish");
+        }
+
+        let mut replies = 0;
+        let mut published = 0;
+        while replies < count {
+            let frame = tokio::time::timeout(Duration::from_secs(10), connection.next()).await
+                .expect("flight did not reply")
+                .expect("socket closed")
+                .expect("socket failed");
+            if let Frame::Text(text) = frame {
+                let reply: serde_json::Value = serde_json::from_slice(&text).expect("invalid reply");
+                match reply["type"].as_str() {
+                    Some("response") => {
+                        replies += 1;
+                        if reply["published"] == true {
+                            published += 1;
+                        }
+                    }
+                    Some("error") => replies += 1,
+                    _ => {}
+                }
+            }
+        }
+        published
+    }
+
+    async fn retries(&self, client: &reqwest::Client) -> u64 {
+        let metrics = client.get(&self.metrics_url)
+            .basic_auth(USERNAME, Some(PASSWORD))
+            .send().await
+            .expect("failed to read metrics")
+            .text().await
+            .expect("failed to read metrics");
+        metrics.lines()
+            .find_map(|line| line.strip_prefix("flight_upstream_retries_total{call=\"publish\"} "))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Default)]
+struct Received {
+    // topic, sequence number and payload of every event flight received
+    events: Mutex<Vec<(String, u64, String)>>
+}
+
+async fn receive(req: HttpRequest, path: web::Path<(String,)>, body: web::Bytes, received: web::Data<Arc<Received>>) -> HttpResponse {
+    let (topic,) = path.into_inner();
+    let seq = req.headers().get("Flock-Sequence")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .unwrap_or(0);
+    received.events.lock().unwrap().push((topic, seq, String::from_utf8_lossy(&body).to_string()));
+    HttpResponse::Ok().json(serde_json::json!({ "delivered": 1 }))
+}
+
+// starts a fake flight instance on a free port, returning the port
+fn start_flight(received: Arc<Received>) -> u16 {
+    let server = HttpServer::new(move || App::new()
+        .data(received.clone())
+        .route("/api/v1/event/{topic:.*}", web::post().to(receive))
+    )
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .expect("failed to bind flight");
+    let port = server.addrs()[0].port();
+    // the server keeps running on the system of the test once started
+    drop(server.run());
+    port
+}
+
+fn unique_topic(name: &str) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    format!("test-{}-{}-{}", name, std::process::id(), nanos)
+}
+
+async fn wait_ready(client: &reqwest::Client, instance: &TrafficControl) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        let res = client.get(&format!("{}/api/v1/history/ready", instance.base_url))
+            .basic_auth(USERNAME, Some(PASSWORD))
+            .send().await;
+        if res.is_ok_and(|res| res.status().is_success()) {
+            return;
+        }
+        actix::clock::delay_for(Duration::from_millis(100)).await;
+    }
+    panic!("{} did not start", instance.base_url);
+}
+
+async fn wait_for(received: &Received, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while received.events.lock().unwrap().len() < count && Instant::now() < deadline {
+        actix::clock::delay_for(Duration::from_millis(50)).await;
+    }
+    // anything arriving late would be a duplicate
+    actix::clock::delay_for(Duration::from_millis(500)).await;
+}
+
+async fn put(client: &reqwest::Client, url: &str, flight_port: u16) -> reqwest::StatusCode {
+    client.put(url)
+        .query(&[("host", "127.0.0.1"), ("port", &flight_port.to_string())])
+        .basic_auth(USERNAME, Some(PASSWORD))
+        .send().await
+        .expect("request failed")
+        .status()
+}
+
+async fn reset(client: &reqwest::Client, instance: &TrafficControl, flight_port: u16) {
+    let _ = client.delete(&format!("{}/api/v1/subscription", instance.base_url))
+        .query(&[("host", "127.0.0.1"), ("port", &flight_port.to_string())])
+        .basic_auth(USERNAME, Some(PASSWORD))
+        .send().await;
+}
+
+#[test]
+#[ignore]
+fn events_published_through_either_instance_are_delivered_once() {
+    const EVENTS: usize = 200;
+
+    actix_web::rt::System::new("multi_instance").block_on(async {
+        let first = TrafficControl::spawn();
+        let second = TrafficControl::spawn();
+        let client = reqwest::Client::new();
+        wait_ready(&client, &first).await;
+        wait_ready(&client, &second).await;
+
+        let received = Arc::new(Received::default());
+        let flight_port = start_flight(received.clone());
+        let topic = unique_topic("fanout");
+
+        // the subscription is made through one instance and used by both
+        let status = put(&client, &format!("{}/api/v1/subscription/{}", first.base_url, topic), flight_port).await;
+        assert!(status.is_success());
+
+        // flight takes the instances in turn
+        let flight = Flight::spawn(&[&first, &second]);
+        let mut connection = flight.connect().await;
+        assert_eq!(flight.publish(&mut connection, &topic, 0..EVENTS).await, EVENTS);
+        wait_for(&received, EVENTS).await;
+
+        let events = received.events.lock().unwrap().clone();
+        assert_eq!(events.len(), EVENTS, "events were lost or duplicated");
+        assert!(events.iter().all(|(event_topic, _, _)| event_topic == &topic));
+        // the instances share the sequence of the topic, so that every event has a number of its own
+        let seqs: BTreeSet<u64> = events.iter().map(|(_, seq, _)| *seq).collect();
+        assert_eq!(seqs, (1..=EVENTS as u64).collect());
+        let payloads: BTreeSet<String> = events.iter().map(|(_, _, payload)| payload.to_string()).collect();
+        assert_eq!(payloads, (0..EVENTS).map(|n| format!("{{\"n\":{}}}", n)).collect());
+
+        reset(&client, &second, flight_port).await;
+    });
+}
+
+#[test]
+#[ignore]
+fn remaining_instance_takes_over_when_one_stops() {
+    const EVENTS: usize = 6;
+
+    actix_web::rt::System::new("multi_instance").block_on(async {
+        let first = TrafficControl::spawn();
+        let second = TrafficControl::spawn();
+        let client = reqwest::Client::new();
+        wait_ready(&client, &first).await;
+        wait_ready(&client, &second).await;
+
+        let received = Arc::new(Received::default());
+        let flight_port = start_flight(received.clone());
+        let topic = unique_topic("failover");
+
+        // the lease of flight is shared, so that it is only new to the first instance renewing it
+        let heartbeat = |instance: &TrafficControl| format!("{}/api/v1/heartbeat", instance.base_url);
+        reset(&client, &second, flight_port).await;
+        assert_eq!(put(&client, &heartbeat(&first), flight_port).await, reqwest::StatusCode::CREATED);
+        assert_eq!(put(&client, &heartbeat(&second), flight_port).await, reqwest::StatusCode::OK);
+
+        let status = put(&client, &format!("{}/api/v1/subscription/{}", &first.base_url, topic), flight_port).await;
+        assert!(status.is_success());
+
+        let flight = Flight::spawn(&[&first, &second]);
+        let mut connection = flight.connect().await;
+        assert_eq!(flight.publish(&mut connection, &topic, 0..2).await, 2);
+        assert_eq!(flight.retries(&client).await, 0);
+
+        // flight still takes the stopped instance in turn, and publishes on the remaining one instead
+        drop(first);
+        assert_eq!(flight.publish(&mut connection, &topic, 2..EVENTS).await, EVENTS - 2);
+        assert!(flight.retries(&client).await > 0, "no publish was retried");
+        wait_for(&received, EVENTS).await;
+
+        let events = received.events.lock().unwrap().clone();
+        let seqs: BTreeSet<u64> = events.iter().map(|(_, seq, _)| *seq).collect();
+        assert_eq!(events.len(), EVENTS, "events were lost or duplicated");
+        assert_eq!(seqs, (1..=EVENTS as u64).collect());
+
+        reset(&client, &second, flight_port).await;
+    });
+}
@@ -7,6 +7,7 @@ use pyo3::{
 #[derive(Debug, PartialEq)]
 pub enum TrafficControlClientError {
     Unauthorized,
+    BadRequest,
     InternalError
 }
 
@@ -17,10 +18,18 @@ impl From<reqwest::Error> for TrafficControlClientError {
     }
 }
 
+impl From<serde_json::Error> for TrafficControlClientError {
+    fn from(error: serde_json::Error) -> Self {
+        error!("Serde Error: {:?}", error);
+        TrafficControlClientError::BadRequest
+    }
+}
+
 impl From<TrafficControlClientError> for PyErr {
     fn from(err: TrafficControlClientError) -> PyErr {
         match err {
             TrafficControlClientError::Unauthorized => PyException::new_err("Unauthorized"),
+            TrafficControlClientError::BadRequest => PyException::new_err("Bad request"),
             _ => PyException::new_err("Something went wrong")
         }
     }
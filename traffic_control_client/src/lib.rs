@@ -1,14 +1,27 @@
-use std::sync::Arc;
+// pyo3 0.16 expands `#[pymethods]` into an impl nested in a static, which newer compilers warn about
+#![allow(non_local_definitions)]
+
+use std::{collections::HashMap, sync::Arc};
 use log::error;
+use opentelemetry::{
+    global, Context, KeyValue,
+    propagation::TextMapPropagator,
+    sdk::propagation::TraceContextPropagator,
+    trace::{SpanKind, TraceContextExt, Tracer}
+};
 use pyo3::prelude::*;
 
 mod error;
 mod types;
 
+pub use crate::types::PublishReport;
+
 use crate::{
-    types::{Result, Error},
+    types::{Result, Error, BatchEvent},
 };
 
+const TRACEPARENT_HEADER: &str = "traceparent";
+
 #[pyclass]
 pub struct PyTrafficControlClient {
     client: Arc<TrafficControlClient>
@@ -27,22 +40,105 @@ impl PyTrafficControlClient {
         }
     }
 
-    pub fn publish(self_: PyRef<Self>, base_url: &str, topic: &str, payload: &str) -> PyResult<()> {
+    /// Publishes a JSON payload. `traceparent` continues the trace of the caller, if given.
+    #[args(traceparent = "None")]
+    pub fn publish(self_: PyRef<Self>, base_url: &str, topic: &str, payload: &str, traceparent: Option<&str>) -> PyResult<()> {
+        self_.post(base_url, topic, payload.as_bytes().to_vec(), JSON_CONTENT_TYPE, traceparent, false)?;
+        Ok(())
+    }
+
+    #[args(traceparent = "None")]
+    pub fn publish_bytes(self_: PyRef<Self>, base_url: &str, topic: &str, payload: &[u8], traceparent: Option<&str>) -> PyResult<()> {
+        self_.post(base_url, topic, payload.to_vec(), BINARY_CONTENT_TYPE, traceparent, false)?;
+        Ok(())
+    }
+
+    /// Publishes a JSON payload and waits for the flight instances to acknowledge it.
+    #[args(traceparent = "None")]
+    pub fn publish_with_report(self_: PyRef<Self>, base_url: &str, topic: &str, payload: &str, traceparent: Option<&str>) -> PyResult<PublishReport> {
+        let report = self_.post(base_url, topic, payload.as_bytes().to_vec(), JSON_CONTENT_TYPE, traceparent, true)?;
+        Ok(report.ok_or(Error::InternalError)?)
+    }
+
+    #[args(traceparent = "None")]
+    pub fn publish_bytes_with_report(self_: PyRef<Self>, base_url: &str, topic: &str, payload: &[u8], traceparent: Option<&str>) -> PyResult<PublishReport> {
+        let report = self_.post(base_url, topic, payload.to_vec(), BINARY_CONTENT_TYPE, traceparent, true)?;
+        Ok(report.ok_or(Error::InternalError)?)
+    }
+
+    /// Publishes JSON payloads to several topics with a single request, given as `(topic, payload)` pairs.
+    #[args(traceparent = "None")]
+    pub fn publish_batch(self_: PyRef<Self>, base_url: &str, events: Vec<(String, String)>, traceparent: Option<&str>) -> PyResult<()> {
         let mut rt = tokio::runtime::Runtime::new().unwrap();
         let client = self_.client.clone();
+        let parent = parse_traceparent(traceparent);
+        let events: Vec<(&str, &str)> = events.iter().map(|(topic, payload)| (topic.as_str(), payload.as_str())).collect();
         rt.block_on(async {
-            client.publish(base_url, topic, payload).await
+            client.post_events(base_url, &events, &parent, false).await
         })?;
         Ok(())
     }
+
+    /// Publishes a batch and waits for the flight instances to acknowledge its events.
+    #[args(traceparent = "None")]
+    pub fn publish_batch_with_report(self_: PyRef<Self>, base_url: &str, events: Vec<(String, String)>, traceparent: Option<&str>) -> PyResult<PublishReport> {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let client = self_.client.clone();
+        let parent = parse_traceparent(traceparent);
+        let events: Vec<(&str, &str)> = events.iter().map(|(topic, payload)| (topic.as_str(), payload.as_str())).collect();
+        let report = rt.block_on(async {
+            client.post_events(base_url, &events, &parent, true).await
+        })?;
+        Ok(report.ok_or(Error::InternalError)?)
+    }
+}
+
+impl PyTrafficControlClient {
+    fn post(&self, base_url: &str, topic: &str, payload: Vec<u8>, content_type: &str, traceparent: Option<&str>, report: bool) -> Result<Option<PublishReport>> {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let client = self.client.clone();
+        let parent = parse_traceparent(traceparent);
+        rt.block_on(async {
+            client.post_event(base_url, topic, payload, content_type, &parent, report).await
+        })
+    }
+}
+
+fn parse_traceparent(traceparent: Option<&str>) -> Context {
+    let mut carrier = HashMap::new();
+    if let Some(traceparent) = traceparent {
+        carrier.insert(TRACEPARENT_HEADER.to_string(), traceparent.to_string());
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+// the span is only recorded when the application installed a tracer, but its context is forwarded regardless
+fn start_span(parent: &Context, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer("traffic_control_client");
+    let span = tracer.span_builder("publish")
+        .with_kind(SpanKind::Client)
+        .with_parent_context(parent.clone())
+        .with_attributes(attributes)
+        .start(&tracer);
+    parent.with_span(span)
+}
+
+fn inject_traceparent(cx: &Context) -> Option<String> {
+    let mut headers = HashMap::new();
+    TraceContextPropagator::new().inject_context(cx, &mut headers);
+    headers.remove(TRACEPARENT_HEADER)
 }
 
 #[pymodule]
 fn traffic_control_client(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyTrafficControlClient>()?;
+    m.add_class::<PublishReport>()?;
     Ok(())
 }
 
+const JSON_CONTENT_TYPE: &str = "application/json";
+const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
 pub struct TrafficControlClient {
     client: reqwest::Client,
     username: String,
@@ -58,15 +154,97 @@ impl TrafficControlClient {
         }
     }
 
+    /// Publishes a JSON payload as part of the current trace, if any.
     pub async fn publish(&self, base_url: &str, topic: &str, payload: &str) -> Result<()> {
+        self.post_event(base_url, topic, payload.as_bytes().to_vec(), JSON_CONTENT_TYPE, &Context::current(), false).await?;
+        Ok(())
+    }
+
+    /// Publishes a binary payload, which is delivered to sockets as a binary frame.
+    pub async fn publish_bytes(&self, base_url: &str, topic: &str, payload: &[u8]) -> Result<()> {
+        self.post_event(base_url, topic, payload.to_vec(), BINARY_CONTENT_TYPE, &Context::current(), false).await?;
+        Ok(())
+    }
+
+    /// Publishes a JSON payload and waits for the flight instances to acknowledge it,
+    /// so that partial deliveries can be detected.
+    pub async fn publish_with_report(&self, base_url: &str, topic: &str, payload: &str) -> Result<PublishReport> {
+        self.post_event(base_url, topic, payload.as_bytes().to_vec(), JSON_CONTENT_TYPE, &Context::current(), true).await?
+            .ok_or(Error::InternalError)
+    }
+
+    /// Publishes a binary payload and waits for the flight instances to acknowledge it.
+    pub async fn publish_bytes_with_report(&self, base_url: &str, topic: &str, payload: &[u8]) -> Result<PublishReport> {
+        self.post_event(base_url, topic, payload.to_vec(), BINARY_CONTENT_TYPE, &Context::current(), true).await?
+            .ok_or(Error::InternalError)
+    }
+
+    /// Publishes JSON payloads to several topics with a single request, given as `(topic, payload)` pairs.
+    pub async fn publish_batch(&self, base_url: &str, events: &[(&str, &str)]) -> Result<()> {
+        self.post_events(base_url, events, &Context::current(), false).await?;
+        Ok(())
+    }
+
+    /// Publishes a batch and waits for the flight instances to acknowledge its events,
+    /// each of which counts once for every instance it was sent to.
+    pub async fn publish_batch_with_report(&self, base_url: &str, events: &[(&str, &str)]) -> Result<PublishReport> {
+        self.post_events(base_url, events, &Context::current(), true).await?
+            .ok_or(Error::InternalError)
+    }
+
+    async fn post_events(&self, base_url: &str, events: &[(&str, &str)], parent: &Context, report: bool) -> Result<Option<PublishReport>> {
+        let url = format!("{}/api/v1/events", base_url);
+        let events = events.iter()
+            .map(|(topic, payload)| Ok(BatchEvent { topic, payload: serde_json::from_str(payload)? }))
+            .collect::<Result<Vec<BatchEvent>>>()?;
+
+        let cx = start_span(parent, vec![KeyValue::new("events", events.len() as i64)]);
+        let mut req = self.client.post(&url).json(&events)
+            .basic_auth(&self.username, Some(&self.password));
+        if report {
+            req = req.query(&[("report", "true")]);
+        }
+        if let Some(traceparent) = inject_traceparent(&cx) {
+            req = req.header(TRACEPARENT_HEADER, traceparent);
+        }
+        match req.send().await {
+            Ok(res) => {
+                if res.status() == reqwest::StatusCode::NO_CONTENT {
+                    Ok(None)
+                } else if res.status() == reqwest::StatusCode::OK {
+                    Ok(Some(res.json::<PublishReport>().await?))
+                } else if res.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    Err(Error::Unauthorized)
+                } else if res.status() == reqwest::StatusCode::BAD_REQUEST {
+                    Err(Error::BadRequest)
+                } else {
+                    error!("Unexpected status code {}", res.status());
+                    Err(Error::InternalError)
+                }
+            }
+            Err(err) => Err(err.into())
+        }
+    }
+
+    async fn post_event(&self, base_url: &str, topic: &str, payload: Vec<u8>, content_type: &str, parent: &Context, report: bool) -> Result<Option<PublishReport>> {
         let url = format!("{}/api/v1/event/{}", base_url, topic);
-        match self.client.post(&url).body(payload.to_string())
+
+        let cx = start_span(parent, vec![KeyValue::new("topic", topic.to_string())]);
+        let mut req = self.client.post(&url).body(payload)
             .basic_auth(&self.username, Some(&self.password))
-            .header("content-type", "application/json")
-            .send().await {
+            .header("content-type", content_type);
+        if report {
+            req = req.query(&[("report", "true")]);
+        }
+        if let Some(traceparent) = inject_traceparent(&cx) {
+            req = req.header(TRACEPARENT_HEADER, traceparent);
+        }
+        match req.send().await {
             Ok(res) => {
                 if res.status() == reqwest::StatusCode::NO_CONTENT {
-                    Ok(())
+                    Ok(None)
+                } else if res.status() == reqwest::StatusCode::OK {
+                    Ok(Some(res.json::<PublishReport>().await?))
                 } else if res.status() == reqwest::StatusCode::UNAUTHORIZED {
                     Err(Error::Unauthorized)
                 } else {
@@ -1,3 +1,30 @@
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
 use crate::error::TrafficControlClientError;
 pub type Error = TrafficControlClientError;
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// An event of a batch publish.
+#[derive(Serialize)]
+pub struct BatchEvent<'a> {
+    pub topic: &'a str,
+    pub payload: serde_json::Value
+}
+
+/// Outcome of a publish, as reported by traffic-control.
+#[pyclass]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PublishReport {
+    /// Flight instances the event was sent to.
+    #[pyo3(get)]
+    pub targeted: usize,
+    /// Flight instances that acknowledged the event, unknown when flight instances receive events from Redis.
+    #[pyo3(get)]
+    pub succeeded: Option<usize>,
+    /// Flight instances that missed the event, unknown when flight instances receive events from Redis.
+    #[pyo3(get)]
+    pub failed: Option<usize>,
+    /// Sockets the event reached, unknown when flight instances receive events from Redis.
+    #[pyo3(get)]
+    pub sockets: Option<usize>
+}